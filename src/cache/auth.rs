@@ -1,27 +1,90 @@
-use std::sync::Arc;
+use std::{future::Future, net::IpAddr, sync::Arc};
 
 use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use moka::future::Cache;
 
-use crate::{database::models::UserOrder, utils::config::AuthCacheConfig};
+use crate::{
+  database::models::UserOrder,
+  utils::{
+    config::AuthCacheConfig,
+    header_rule::{valid_rules, HeaderRule},
+  },
+};
 
 pub struct AuthCacheValue {
+  pub order_id: String,
+  /// Product slug of the order, selects the product specific proxy settings.
+  pub product: String,
   pub use_credentials: bool,
+  /// Username and password of the address, `None` when the order only authenticates with tokens.
+  pub credentials: Option<Credentials>,
+  /// Bearer API tokens accepted in place of the username and password.
+  pub tokens: Vec<String>,
+  /// Request header rewrites of the order, applied after the product ones.
+  pub header_rules: Vec<HeaderRule>,
+  pub whitelist: Vec<IpNet>,
+  pub expiration: DateTime<Utc>,
+  /// Maximum requests allowed for the order, 0 means unlimited.
+  pub requests_limit: u64,
+  /// Requests usage persisted in the backend when the value was fetched.
+  pub requests_usage: u64,
+}
+
+#[derive(PartialEq)]
+pub struct Credentials {
   pub username: String,
   pub password: String,
-  pub whitelist: Vec<String>,
-  pub expiration: DateTime<Utc>,
+}
+
+impl Credentials {
+  /// Picks the credentials at `pos`, an empty username or password never makes a usable pair.
+  pub fn at(usernames: &[String], passwords: &[String], pos: usize) -> Option<Self> {
+    let username = usernames.get(pos).filter(|username| !username.is_empty())?;
+    let password = passwords.get(pos).filter(|password| !password.is_empty())?;
+    Some(Self {
+      username: username.clone(),
+      password: password.clone(),
+    })
+  }
+}
+
+impl AuthCacheValue {
+  pub fn is_whitelisted(&self, client_ip: IpAddr) -> bool {
+    // IPv4 clients reaching a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+    let client_ip = client_ip.to_canonical();
+    self.whitelist.iter().any(|net| net.contains(&client_ip))
+  }
+}
+
+/// Parses whitelist entries, either single IPv4/IPv6 addresses or CIDR ranges. Invalid entries are reported and skipped.
+pub fn parse_whitelist(key: &str, entries: &[String]) -> Vec<IpNet> {
+  let mut whitelist = Vec::with_capacity(entries.len());
+  for entry in entries {
+    let entry = entry.trim();
+    if let Ok(net) = entry.parse::<IpNet>() {
+      whitelist.push(net);
+    } else if let Ok(addr) = entry.parse::<IpAddr>() {
+      whitelist.push(IpNet::from(addr.to_canonical()));
+    } else {
+      warn!("invalid whitelist entry {:?} for {}, ignored", entry, key);
+    }
+  }
+  whitelist
 }
 
 #[derive(Clone)]
 pub struct AuthCache {
   pub inner: Cache<String, Arc<AuthCacheValue>>,
+  // Addresses known to have no order, kept for a short time to spare the backend from repeated misses.
+  negative: Cache<String, ()>,
 }
 
 impl AuthCache {
   pub fn new(config: AuthCacheConfig) -> Self {
     Self {
       inner: Cache::builder().max_capacity(config.max_size).time_to_live(config.time_to_live).build(),
+      negative: Cache::builder().max_capacity(config.max_size).time_to_live(config.negative_ttl).build(),
     }
   }
 
@@ -29,20 +92,106 @@ impl AuthCache {
     self.inner.get(key)
   }
 
+  pub fn is_negative(&self, key: &str) -> bool {
+    self.negative.contains_key(key)
+  }
+
+  /// Returns the cached value or runs `load`, concurrent calls for the same key wait for a single load.
+  pub async fn get_or_load<F>(&self, key: &str, load: F) -> Option<Arc<AuthCacheValue>>
+  where
+    F: Future<Output = Option<Arc<AuthCacheValue>>>,
+  {
+    self.inner.optionally_get_with_by_ref(key, load).await
+  }
+
+  pub async fn insert_negative(&self, key: &str) {
+    debug!("insert negative auth - {}", key);
+    self.negative.insert(String::from(key), ()).await;
+  }
+
   pub async fn insert(&self, key: &str, doc: UserOrder, credentials_pos: usize) -> Arc<AuthCacheValue> {
-    debug!("insert auth - {}\n{:?}\nPosition: {}", key, doc, credentials_pos);
+    // Never log the whole document, it holds the proxy credentials.
+    debug!("insert auth - {} (Order: {}, Position: {})", key, doc._id, credentials_pos);
     let value = Arc::new(AuthCacheValue {
-      username: doc.proxy.username[credentials_pos].clone(),
-      password: doc.proxy.password[credentials_pos].clone(),
+      order_id: doc._id.to_hex(),
+      product: doc.product_slug.clone(),
+      credentials: Credentials::at(&doc.proxy.username, &doc.proxy.password, credentials_pos),
+      tokens: doc.proxy.tokens.clone(),
+      header_rules: valid_rules(key, &doc.proxy.header_rules),
       use_credentials: doc.proxy.use_credentials,
-      whitelist: doc.proxy.whitelist,
+      whitelist: parse_whitelist(key, &doc.proxy.whitelist),
       expiration: DateTime::from(doc.expiration.to_system_time()),
+      requests_limit: doc.counters.requests_limit,
+      requests_usage: doc.counters.requests_usage,
     });
+    self.negative.invalidate(key).await;
     self.inner.insert(String::from(key), value.clone()).await;
-    return value;
+    value
   }
 
   pub async fn delete(&self, key: &str) {
     self.inner.invalidate(key).await
   }
+
+  pub fn invalidate_all(&self) {
+    self.inner.invalidate_all();
+    self.negative.invalidate_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn whitelisted(entries: &[&str]) -> AuthCacheValue {
+    let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+    AuthCacheValue {
+      order_id: String::from("order"),
+      product: String::from("product"),
+      use_credentials: false,
+      credentials: None,
+      tokens: Vec::new(),
+      header_rules: Vec::new(),
+      whitelist: parse_whitelist("test", &entries),
+      expiration: Utc::now(),
+      requests_limit: 0,
+      requests_usage: 0,
+    }
+  }
+
+  #[test]
+  fn parses_addresses_and_ranges() {
+    let entries = [" 192.0.2.1 ", "198.51.100.0/24", "2001:db8::1", "2001:db8:1::/48"].map(String::from);
+    let whitelist: Vec<String> = parse_whitelist("test", &entries).iter().map(ToString::to_string).collect();
+    assert_eq!(whitelist, ["192.0.2.1/32", "198.51.100.0/24", "2001:db8::1/128", "2001:db8:1::/48"]);
+  }
+
+  #[test]
+  fn skips_invalid_entries() {
+    let entries = ["", "example.com", "192.0.2.0/33", "192.0.2.1"].map(String::from);
+    assert_eq!(parse_whitelist("test", &entries), vec!["192.0.2.1/32".parse::<IpNet>().unwrap()]);
+  }
+
+  #[test]
+  fn matches_addresses_and_ranges() {
+    let value = whitelisted(&["192.0.2.1", "198.51.100.0/24", "2001:db8:1::/48"]);
+    assert!(value.is_whitelisted("192.0.2.1".parse().unwrap()));
+    assert!(!value.is_whitelisted("192.0.2.2".parse().unwrap()));
+    assert!(value.is_whitelisted("198.51.100.200".parse().unwrap()));
+    assert!(value.is_whitelisted("2001:db8:1::42".parse().unwrap()));
+    assert!(!value.is_whitelisted("2001:db8:2::42".parse().unwrap()));
+  }
+
+  #[test]
+  fn matches_ipv4_mapped_peers() {
+    let value = whitelisted(&["192.0.2.1", "198.51.100.0/24"]);
+    assert!(value.is_whitelisted("::ffff:192.0.2.1".parse().unwrap()));
+    assert!(value.is_whitelisted("::ffff:198.51.100.7".parse().unwrap()));
+    assert!(!value.is_whitelisted("::ffff:192.0.2.2".parse().unwrap()));
+
+    // A mapped entry is stored as the IPv4 address it maps.
+    let value = whitelisted(&["::ffff:192.0.2.1"]);
+    assert!(value.is_whitelisted("192.0.2.1".parse().unwrap()));
+    assert!(value.is_whitelisted("::ffff:192.0.2.1".parse().unwrap()));
+  }
 }
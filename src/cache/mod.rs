@@ -1 +1,3 @@
 pub mod auth;
+pub mod session;
+pub mod usage;
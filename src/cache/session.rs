@@ -0,0 +1,156 @@
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use chrono::Utc;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use super::{auth::AuthCacheValue, usage::BandwidthCounter};
+
+struct SessionState {
+  token: CancellationToken,
+  // Order state the session was authorized with, refreshed when the order is renewed.
+  cache_value: Mutex<Arc<AuthCacheValue>>,
+  // Notified whenever `cache_value` is replaced, its expiration may have moved.
+  changed: Notify,
+}
+
+// Sessions by id, per egress address.
+type Sessions = HashMap<IpAddr, HashMap<u64, Arc<SessionState>>>;
+
+/// Live proxy sessions keyed by egress address, used to terminate them when their order changes.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+  sessions: Arc<Mutex<Sessions>>,
+  next_id: Arc<AtomicU64>,
+}
+
+impl SessionRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn register(&self, egress_addr: IpAddr, cache_value: Arc<AuthCacheValue>, bandwidth: Arc<BandwidthCounter>) -> Session {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let state = Arc::new(SessionState {
+      token: CancellationToken::new(),
+      cache_value: Mutex::new(cache_value),
+      changed: Notify::new(),
+    });
+    self.sessions.lock().unwrap().entry(egress_addr).or_default().insert(id, state.clone());
+
+    Session {
+      registry: self.clone(),
+      egress_addr,
+      id,
+      state,
+      bandwidth,
+    }
+  }
+
+  /// Egress addresses with live sessions.
+  pub fn addresses(&self) -> Vec<IpAddr> {
+    self.sessions.lock().unwrap().keys().copied().collect()
+  }
+
+  /// Terminates every session relayed through the egress address.
+  pub fn terminate_address(&self, egress_addr: IpAddr) {
+    if let Some(sessions) = self.sessions.lock().unwrap().get(&egress_addr) {
+      if !sessions.is_empty() {
+        info!("terminating {} sessions on {}", sessions.len(), egress_addr);
+      }
+      sessions.values().for_each(|state| state.token.cancel());
+    }
+  }
+
+  /// Terminates every session belonging to the order, whatever egress address it uses.
+  pub fn terminate_order(&self, order_id: &str) {
+    let sessions = self.sessions.lock().unwrap();
+    for (egress_addr, sessions) in sessions.iter() {
+      for state in sessions.values() {
+        if state.cache_value.lock().unwrap().order_id == order_id {
+          info!("terminating session of order {} on {}", order_id, egress_addr);
+          state.token.cancel();
+        }
+      }
+    }
+  }
+
+  /// Terminates the sessions on the egress address which are no longer authorized by the current order state,
+  /// the remaining ones pick up the new expiration.
+  pub fn revalidate_address(&self, egress_addr: IpAddr, current: &Arc<AuthCacheValue>) {
+    if let Some(sessions) = self.sessions.lock().unwrap().get(&egress_addr) {
+      for state in sessions.values() {
+        let mut cache_value = state.cache_value.lock().unwrap();
+        if cache_value.order_id != current.order_id
+          || cache_value.use_credentials != current.use_credentials
+          || cache_value.credentials != current.credentials
+          || cache_value.tokens != current.tokens
+          || cache_value.whitelist != current.whitelist
+          || current.expiration <= Utc::now()
+        {
+          info!("terminating session of order {} on {}, order changed", cache_value.order_id, egress_addr);
+          state.token.cancel();
+        } else {
+          *cache_value = current.clone();
+          state.changed.notify_waiters();
+        }
+      }
+    }
+  }
+
+  fn remove(&self, egress_addr: IpAddr, id: u64) {
+    let mut sessions = self.sessions.lock().unwrap();
+    if let Some(entries) = sessions.get_mut(&egress_addr) {
+      entries.remove(&id);
+      if entries.is_empty() {
+        sessions.remove(&egress_addr);
+      }
+    }
+  }
+}
+
+/// Handle of an authorized connection, unregistered on drop.
+pub struct Session {
+  registry: SessionRegistry,
+  egress_addr: IpAddr,
+  id: u64,
+  state: Arc<SessionState>,
+  pub bandwidth: Arc<BandwidthCounter>,
+}
+
+impl Session {
+  /// Resolves once the session has been terminated or its order has expired.
+  pub async fn terminated(&self) {
+    loop {
+      // Registered before reading the expiration, so a change made meanwhile is not missed.
+      let changed = self.state.changed.notified();
+      tokio::pin!(changed);
+      changed.as_mut().enable();
+
+      let expiration = self.state.cache_value.lock().unwrap().expiration;
+      let remaining = (expiration - Utc::now()).to_std().unwrap_or_default();
+      if remaining.is_zero() {
+        return debug!("session on {} reached order expiration", self.egress_addr);
+      }
+      tokio::select! {
+        _ = self.state.token.cancelled() => return,
+        // The order may have been renewed or shortened meanwhile, check the expiration again.
+        _ = changed => (),
+        _ = tokio::time::sleep(remaining) => (),
+      }
+    }
+  }
+}
+
+impl Drop for Session {
+  fn drop(&mut self) {
+    self.registry.remove(self.egress_addr, self.id);
+  }
+}
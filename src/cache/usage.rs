@@ -0,0 +1,154 @@
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, Weak,
+  },
+};
+
+use chrono::{DateTime, Utc};
+
+use super::auth::AuthCacheValue;
+
+struct RequestCounter {
+  // Best known total usage for the order, persisted + pending.
+  usage: u64,
+  // Requests counted since the last flush to the backend.
+  pending: u64,
+  // Latest order state seen, the counter outlives it only while requests are pending.
+  order: Weak<AuthCacheValue>,
+}
+
+/// Bytes relayed for one order through one egress address, shared by every session of the pair.
+/// `bytes_in` is received from the targets, `bytes_out` is sent to the targets.
+#[derive(Default)]
+pub struct BandwidthCounter {
+  bytes_in: AtomicU64,
+  bytes_out: AtomicU64,
+}
+
+impl BandwidthCounter {
+  pub fn add_in(&self, bytes: u64) {
+    self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub fn add_out(&self, bytes: u64) {
+    self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  fn take(&self) -> (u64, u64) {
+    (self.bytes_in.swap(0, Ordering::Relaxed), self.bytes_out.swap(0, Ordering::Relaxed))
+  }
+}
+
+// Keyed by (order id, egress address).
+type BandwidthCounters = HashMap<(String, IpAddr), Arc<BandwidthCounter>>;
+
+pub struct BandwidthRecord {
+  pub order_id: String,
+  pub address: IpAddr,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+  pub start: DateTime<Utc>,
+  pub end: DateTime<Utc>,
+}
+
+/// In-memory per-order request and bandwidth accounting, periodically flushed to the auth backend.
+#[derive(Clone, Default)]
+pub struct UsageCache {
+  requests: Arc<Mutex<HashMap<String, RequestCounter>>>,
+  bandwidth: Arc<Mutex<BandwidthCounters>>,
+}
+
+impl UsageCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Counts one request for the order, returns false once the order limit has been reached.
+  pub fn try_consume_request(&self, cache_value: &Arc<AuthCacheValue>) -> bool {
+    let mut requests = self.requests.lock().unwrap();
+    let counter = requests.entry(cache_value.order_id.clone()).or_insert(RequestCounter {
+      usage: cache_value.requests_usage,
+      pending: 0,
+      order: Weak::new(),
+    });
+    counter.order = Arc::downgrade(cache_value);
+
+    // The cache value may have been refreshed with a persisted usage including other instances' requests.
+    counter.usage = counter.usage.max(cache_value.requests_usage + counter.pending);
+
+    if cache_value.requests_limit != 0 && counter.usage >= cache_value.requests_limit {
+      return false;
+    }
+
+    counter.usage += 1;
+    counter.pending += 1;
+    true
+  }
+
+  /// Takes the pending request counts, resetting them. Idle counters are forgotten once the order state they last
+  /// saw has left the auth cache, the next request starts again from a refreshed `requests_usage`.
+  pub fn take_pending_requests(&self) -> HashMap<String, u64> {
+    let mut requests = self.requests.lock().unwrap();
+    let mut pending = HashMap::new();
+    requests.retain(|order_id, counter| {
+      if counter.pending == 0 {
+        return counter.order.strong_count() > 0;
+      }
+      pending.insert(order_id.clone(), counter.pending);
+      counter.pending = 0;
+      true
+    });
+    pending
+  }
+
+  /// Puts back pending request counts which could not be flushed.
+  pub fn restore_pending_requests(&self, pending: HashMap<String, u64>) {
+    let mut requests = self.requests.lock().unwrap();
+    for (order_id, count) in pending {
+      if let Some(counter) = requests.get_mut(&order_id) {
+        counter.pending += count;
+      }
+    }
+  }
+
+  pub fn bandwidth_counter(&self, order_id: &str, address: IpAddr) -> Arc<BandwidthCounter> {
+    let mut bandwidth = self.bandwidth.lock().unwrap();
+    bandwidth.entry((order_id.to_string(), address)).or_default().clone()
+  }
+
+  /// Takes the bandwidth relayed since the last flush, resetting the counters. Counters no session meters through
+  /// anymore are dropped once drained, they are created again by the next session.
+  pub fn take_bandwidth(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<BandwidthRecord> {
+    let mut bandwidth = self.bandwidth.lock().unwrap();
+    let mut records = Vec::new();
+    bandwidth.retain(|(order_id, address), counter| {
+      // Checked before draining, new sessions can only get the counter through the locked map.
+      let unused = Arc::strong_count(counter) == 1;
+      let (bytes_in, bytes_out) = counter.take();
+      if bytes_in > 0 || bytes_out > 0 {
+        records.push(BandwidthRecord {
+          order_id: order_id.clone(),
+          address: *address,
+          bytes_in,
+          bytes_out,
+          start,
+          end,
+        });
+      }
+      !unused
+    });
+    records
+  }
+
+  /// Puts back bandwidth records which could not be flushed, they are merged into the next flush period.
+  pub fn restore_bandwidth(&self, records: Vec<BandwidthRecord>) {
+    for record in records {
+      let counter = self.bandwidth_counter(&record.order_id, record.address);
+      counter.add_in(record.bytes_in);
+      counter.add_out(record.bytes_out);
+    }
+  }
+}
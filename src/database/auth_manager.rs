@@ -1,109 +1,191 @@
+use std::{
+  net::{IpAddr, SocketAddr},
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+};
+
 use chrono::Utc;
-use mongodb::bson::{doc, oid::ObjectId};
-use std::{net::SocketAddr, sync::Arc};
-use tokio_stream::StreamExt;
+use moka::future::Cache;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 
-use crate::cache::auth::{AuthCache, AuthCacheValue};
+use crate::{
+  cache::{
+    auth::AuthCacheValue,
+    session::{Session, SessionRegistry},
+    usage::UsageCache,
+  },
+  utils::{
+    config::{CredentialsCacheConfig, NoncesCacheConfig, UsageConfig},
+    digest::DigestCredentials,
+    password::is_hashed,
+  },
+};
 
-use super::models::{Stock, UserOrder};
+use super::backend::AuthBackend;
 
+/// Cheap to clone handle used by the proxy handlers, delegates to the configured `AuthBackend`.
 #[derive(Clone)]
 pub struct AuthManager {
-  cache: AuthCache,
-  orders: mongodb::Collection<UserOrder>,
-  stock: mongodb::Collection<Stock>,
+  backend: Arc<dyn AuthBackend>,
+  usage: UsageCache,
+  usage_config: UsageConfig,
+  sessions: SessionRegistry,
+  // Outcome of hashed credentials verifications, keyed by a digest of the stored and provided credentials.
+  verified: Cache<[u8; 32], bool>,
+  // Issued Digest nonces with the highest nonce count seen, to reject replayed requests.
+  nonces: Cache<String, Arc<AtomicU32>>,
+}
+
+pub enum DigestOutcome {
+  Valid,
+  /// Valid credentials with an expired or unknown nonce, the client should retry with a fresh one.
+  Stale,
+  Invalid,
 }
 
 impl AuthManager {
-  pub async fn new(client: &mongodb::Client, database: &str, cache: AuthCache) -> Self {
-    let db = client.database(database);
+  pub fn new(
+    backend: Arc<dyn AuthBackend>,
+    usage_config: UsageConfig,
+    sessions: SessionRegistry,
+    credentials_config: CredentialsCacheConfig,
+    nonces_config: NoncesCacheConfig,
+  ) -> Self {
     Self {
-      cache,
-      orders: db.collection("orders"),
-      stock: db.collection("stock"),
+      backend,
+      usage: UsageCache::new(),
+      usage_config,
+      sessions,
+      verified: Cache::builder()
+        .max_capacity(credentials_config.max_size)
+        .time_to_live(credentials_config.time_to_live)
+        .build(),
+      nonces: Cache::builder()
+        .max_capacity(nonces_config.max_size)
+        .time_to_live(nonces_config.time_to_live)
+        .build(),
     }
   }
 
-  async fn get_related_proxies(&self, order_id: ObjectId) -> Vec<String> {
-    let mut addrs: Vec<String> = Vec::new();
-    let filter = doc! {
-      "used_in_order": order_id,
+  pub async fn get_or_fetch_and_insert(&self, proxy_addr: &SocketAddr) -> Option<Arc<AuthCacheValue>> {
+    self.backend.lookup(proxy_addr).await
+  }
+
+  pub async fn check_credentials(&self, cache_value: Arc<AuthCacheValue>, username: &str, password: &str) -> bool {
+    let stored_hash = match cache_value.credentials.as_ref() {
+      Some(stored) if is_hashed(&stored.password) && cache_value.expiration > Utc::now() => stored.password.clone(),
+      _ => return self.backend.check_credentials(cache_value, username, password).await,
     };
-    if let Ok(mut docs) = self.stock.find(filter, None).await {
-      while let Some(result) = docs.next().await {
-        match result {
-          Ok(entry) => addrs.push(entry.address),
-          _ => (),
-        }
-      }
+
+    // The stored hash is part of the key, so rotated credentials are never matched against a stale outcome.
+    let key = AuthManager::verified_key(&[&cache_value.order_id, &stored_hash, username, password]);
+    if let Some(verified) = self.verified.get(&key) {
+      return verified;
     }
-    return addrs;
+    let verified = self.backend.check_credentials(cache_value, username, password).await;
+    self.verified.insert(key, verified).await;
+    verified
   }
 
-  pub async fn get_or_fetch_and_insert(&self, proxy_addr: &SocketAddr) -> Option<Arc<AuthCacheValue>> {
-    debug!("get_or_fetch_and_insert {} from cache", proxy_addr);
-    if let Some(cv) = self.cache.get(proxy_addr.ip().to_string().as_str()) {
-      return Some(cv);
+  pub async fn check_token(&self, cache_value: Arc<AuthCacheValue>, token: &str) -> bool {
+    if !cache_value.tokens.iter().any(|stored| is_hashed(stored)) || cache_value.expiration <= Utc::now() {
+      return self.backend.check_token(cache_value, token).await;
     }
 
-    let stock = match self
-      .stock
-      .find_one(
-        doc! {
-          "address": proxy_addr.ip().to_string(),
-        },
-        None,
-      )
-      .await
-    {
-      Ok(s) => s,
-      Err(_) => {
-        return None;
-      }
+    let mut parts = vec![cache_value.order_id.as_str(), token];
+    parts.extend(cache_value.tokens.iter().map(String::as_str));
+    let key = AuthManager::verified_key(&parts);
+    if let Some(verified) = self.verified.get(&key) {
+      return verified;
+    }
+    let verified = self.backend.check_token(cache_value.clone(), token).await;
+    self.verified.insert(key, verified).await;
+    verified
+  }
+
+  /// Issues a Digest nonce, valid for the nonces cache time to live.
+  pub async fn issue_nonce(&self) -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let nonce = hex::encode(nonce);
+    self.nonces.insert(nonce.clone(), Arc::new(AtomicU32::new(0))).await;
+    nonce
+  }
+
+  pub fn check_digest(&self, cache_value: Arc<AuthCacheValue>, credentials: &DigestCredentials, method: &str) -> DigestOutcome {
+    if !self.backend.check_digest(cache_value, credentials, method) {
+      return DigestOutcome::Invalid;
+    }
+    let nonce_count = match self.nonces.get(&credentials.nonce) {
+      Some(nonce_count) => nonce_count,
+      None => return DigestOutcome::Stale,
     };
+    // Nonce counts only grow, a count already seen is a replayed request.
+    let nc = credentials.nc.unwrap_or_default();
+    if nonce_count.fetch_max(nc, Ordering::Relaxed) >= nc {
+      debug!("replayed digest nonce count {} for user {}", nc, credentials.username);
+      return DigestOutcome::Invalid;
+    }
+    DigestOutcome::Valid
+  }
 
-    if let Some(stock) = stock {
-      let order = match self
-        .orders
-        .find_one(
-          doc! {
-            "_id": stock.used_in_order,
-          },
-          None,
-        )
-        .await
-      {
-        Ok(o) => o,
-        Err(_) => return None,
-      };
-      if let Some(order) = order {
-        let multi_credentials = order.proxy.username.len() != 1 && order.proxy.password.len() != 1;
-        let proxy_list = self.get_related_proxies(order._id).await;
-        let mut cv: Option<Arc<AuthCacheValue>> = None;
-        for (i, proxy) in proxy_list.iter().enumerate() {
-          let mut pos = 0;
-          if multi_credentials {
-            pos = i;
-          }
-          // Save auth cache result to return it at the end of the function.
-          if &proxy_addr.ip().to_string() == proxy {
-            cv = Some(self.cache.insert(proxy, order.clone(), pos).await)
-          } else {
-            self.cache.insert(proxy, order.clone(), pos).await;
-          }
-        }
-        return cv;
-      }
+  // Digest of the length-prefixed parts, so that no two different lists share a key.
+  fn verified_key(parts: &[&str]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+      hasher.update((part.len() as u64).to_be_bytes());
+      hasher.update(part.as_bytes());
     }
+    hasher.finalize().into()
+  }
 
-    return None;
+  pub fn check_whitelist(&self, cache_value: Arc<AuthCacheValue>, client_addr: SocketAddr) -> bool {
+    self.backend.check_whitelist(cache_value, client_addr)
   }
 
-  pub fn check_credentials(&self, cache_value: Arc<AuthCacheValue>, username: &str, password: &str) -> bool {
-    return cache_value.use_credentials && &cache_value.username == username && &cache_value.password == password && cache_value.expiration > Utc::now();
+  /// Counts one request against the order limit, returns false if the limit has been reached.
+  pub fn try_consume_request(&self, cache_value: &Arc<AuthCacheValue>) -> bool {
+    self.usage.try_consume_request(cache_value)
   }
 
-  pub fn check_whitelist(&self, cache_value: Arc<AuthCacheValue>, client_addr: SocketAddr) -> bool {
-    return !cache_value.use_credentials && cache_value.whitelist.contains(&client_addr.ip().to_string()) && cache_value.expiration > Utc::now();
+  /// Registers an authorized connection relayed through the egress address, metering its bandwidth.
+  pub fn open_session(&self, cache_value: &Arc<AuthCacheValue>, egress_addr: IpAddr) -> Session {
+    let bandwidth = self.usage.bandwidth_counter(&cache_value.order_id, egress_addr);
+    self.sessions.register(egress_addr, cache_value.clone(), bandwidth)
+  }
+
+  /// Periodically flushes the usage accumulated in memory to the backend.
+  pub async fn flush_usage(&self) {
+    info!("Started usage flush task (Interval: {:?})", self.usage_config.flush_interval);
+    let mut interval = tokio::time::interval(self.usage_config.flush_interval);
+    let mut period_start = Utc::now();
+    loop {
+      interval.tick().await;
+
+      let mut requests = self.usage.take_pending_requests();
+      if !requests.is_empty() {
+        debug!("flushing requests usage for {} orders", requests.len());
+        if let Err(e) = self.backend.flush_requests(&mut requests).await {
+          warn!("failed to flush requests usage, {} orders left pending. Err = {}", requests.len(), e);
+          self.usage.restore_pending_requests(requests);
+        }
+      }
+
+      let period_end = Utc::now();
+      let mut records = self.usage.take_bandwidth(period_start, period_end);
+      if !records.is_empty() {
+        debug!("flushing bandwidth usage, {} records", records.len());
+        if let Err(e) = self.backend.flush_bandwidth(&mut records).await {
+          warn!("failed to flush bandwidth usage, {} records left pending. Err = {}", records.len(), e);
+          self.usage.restore_bandwidth(records);
+          // Keep the period open so the restored bytes are not attributed to a shorter window.
+          continue;
+        }
+      }
+      period_start = period_end;
+    }
   }
 }
@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AuthBackendError {
+  #[error("mongodb error. Err = {0}")]
+  MongoDB(mongodb::error::Error),
+  #[error("invalid order id {0}")]
+  InvalidOrderId(String),
+  #[error("order {0} has {1} usernames but {2} passwords")]
+  CredentialsMismatch(mongodb::bson::oid::ObjectId, usize, usize),
+  #[error("credentials index {1} assigned to {0} is out of range, order has {2} credentials")]
+  CredentialsIndexOutOfRange(String, usize, usize),
+}
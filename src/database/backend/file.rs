@@ -0,0 +1,116 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use config::{Config, ConfigError};
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use crate::{
+  cache::{
+    auth::{parse_whitelist, AuthCacheValue, Credentials},
+    usage::BandwidthRecord,
+  },
+  utils::header_rule::HeaderRule,
+};
+
+use super::{error::AuthBackendError, AuthBackend};
+
+#[derive(Deserialize)]
+struct FileAuthSource {
+  orders: Vec<FileAuthOrder>,
+}
+
+#[derive(Deserialize)]
+struct FileAuthOrder {
+  id: String,
+  #[serde(default)]
+  product: String,
+  addrs: Vec<String>,
+  #[serde(default)]
+  username: Vec<String>,
+  #[serde(default)]
+  password: Vec<String>,
+  #[serde(default)]
+  tokens: Vec<String>,
+  #[serde(default)]
+  header_rules: Vec<HeaderRule>,
+  #[serde(default)]
+  whitelist: Vec<String>,
+  use_credentials: bool,
+  expiration: DateTime<Utc>,
+  #[serde(default)]
+  requests_limit: u64,
+}
+
+/// Static backend for standalone deployments, orders are loaded once from a TOML file on startup.
+pub struct FileAuthBackend {
+  entries: HashMap<String, Arc<AuthCacheValue>>,
+}
+
+impl FileAuthBackend {
+  pub fn load(path: &str) -> Result<Self, ConfigError> {
+    let source = Config::builder()
+      .add_source(config::File::with_name(path))
+      .build()?
+      .try_deserialize::<FileAuthSource>()?;
+
+    let mut entries = HashMap::new();
+
+    for order in source.orders {
+      if order.username.len() != order.password.len() {
+        return Err(ConfigError::Message(format!("username and password count mismatch for {:?}", order.addrs)));
+      }
+      if let Some(rule) = order.header_rules.iter().find(|rule| !rule.is_valid()) {
+        return Err(ConfigError::Message(format!("invalid header rule {:?} for {:?}", rule, order.addrs)));
+      }
+      if order.use_credentials && order.username.is_empty() && order.tokens.is_empty() {
+        return Err(ConfigError::Message(format!("missing credentials for {:?}", order.addrs)));
+      }
+
+      // Same semantics as MongoDB orders, either one set of credentials or one set per address.
+      let multi_credentials = order.username.len() > 1;
+      if multi_credentials && order.username.len() < order.addrs.len() {
+        return Err(ConfigError::Message(format!("not enough credentials for {:?}", order.addrs)));
+      }
+
+      for (i, addr) in order.addrs.iter().enumerate() {
+        let pos = if multi_credentials { i } else { 0 };
+        let value = AuthCacheValue {
+          order_id: order.id.clone(),
+          product: order.product.clone(),
+          use_credentials: order.use_credentials,
+          credentials: Credentials::at(&order.username, &order.password, pos),
+          tokens: order.tokens.clone(),
+          header_rules: order.header_rules.clone(),
+          whitelist: parse_whitelist(addr, &order.whitelist),
+          expiration: order.expiration,
+          requests_limit: order.requests_limit,
+          // Usage is not persisted by this backend, counting restarts with the process.
+          requests_usage: 0,
+        };
+        entries.insert(addr.clone(), Arc::new(value));
+      }
+    }
+
+    info!("Loaded {} proxy addresses from file auth backend {}", entries.len(), path);
+
+    Ok(Self { entries })
+  }
+}
+
+#[async_trait]
+impl AuthBackend for FileAuthBackend {
+  async fn lookup(&self, proxy_addr: &SocketAddr) -> Option<Arc<AuthCacheValue>> {
+    self.entries.get(&proxy_addr.ip().to_string()).cloned()
+  }
+
+  async fn flush_bandwidth(&self, records: &mut Vec<BandwidthRecord>) -> Result<(), AuthBackendError> {
+    // Nowhere to persist to, the log is the only record kept.
+    for record in records.drain(..) {
+      info!(
+        "bandwidth usage - order {} via {} from {} to {}: in = {}, out = {}",
+        record.order_id, record.address, record.start, record.end, record.bytes_in, record.bytes_out
+      );
+    }
+    Ok(())
+  }
+}
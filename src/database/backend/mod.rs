@@ -0,0 +1,98 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use subtle::ConstantTimeEq;
+
+use crate::{
+  cache::{auth::AuthCacheValue, usage::BandwidthRecord},
+  utils::{
+    digest::DigestCredentials,
+    password::{is_hashed, verify_password},
+  },
+};
+
+use self::error::AuthBackendError;
+
+pub mod error;
+pub mod file;
+pub mod mongo;
+
+/// Source of truth for proxy authentication, queried by the proxy handlers through `AuthManager`.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+  /// Looks up the order assigned to the proxy listen address.
+  async fn lookup(&self, proxy_addr: &SocketAddr) -> Option<Arc<AuthCacheValue>>;
+
+  /// Persists request counts accumulated since the last flush, keyed by order id.
+  /// Flushed entries are removed from the map, whatever remains on error is retried later.
+  async fn flush_requests(&self, requests: &mut HashMap<String, u64>) -> Result<(), AuthBackendError> {
+    requests.clear();
+    Ok(())
+  }
+
+  /// Persists bandwidth relayed since the last flush, same retry semantics as `flush_requests`.
+  async fn flush_bandwidth(&self, records: &mut Vec<BandwidthRecord>) -> Result<(), AuthBackendError> {
+    records.clear();
+    Ok(())
+  }
+
+  async fn check_credentials(&self, cache_value: Arc<AuthCacheValue>, username: &str, password: &str) -> bool {
+    if !cache_value.use_credentials || cache_value.expiration <= Utc::now() {
+      return false;
+    }
+    // Token-only orders have no credentials, an empty stored pair must never match empty client input.
+    let stored = match cache_value.credentials.as_ref() {
+      Some(stored) if !stored.username.is_empty() && !stored.password.is_empty() => stored,
+      _ => return false,
+    };
+    if !bool::from(stored.username.as_bytes().ct_eq(username.as_bytes())) {
+      return false;
+    }
+    if !is_hashed(&stored.password) {
+      return verify_password(&stored.password, password);
+    }
+    // Hash verification is CPU bound (argon2, bcrypt), keep it off the runtime workers.
+    let (hash, password) = (stored.password.clone(), password.to_string());
+    tokio::task::spawn_blocking(move || verify_password(&hash, &password)).await.unwrap_or(false)
+  }
+
+  async fn check_token(&self, cache_value: Arc<AuthCacheValue>, token: &str) -> bool {
+    if !cache_value.use_credentials || cache_value.expiration <= Utc::now() {
+      return false;
+    }
+    // Every token is checked so the timing does not reveal which one matched.
+    let token = token.to_string();
+    let verify = move || cache_value.tokens.iter().fold(false, |valid, stored| verify_password(stored, &token) | valid);
+    tokio::task::spawn_blocking(verify).await.unwrap_or(false)
+  }
+
+  /// Verifies the Digest response, which requires the password to be stored in plaintext.
+  fn check_digest(&self, cache_value: Arc<AuthCacheValue>, credentials: &DigestCredentials, method: &str) -> bool {
+    if !cache_value.use_credentials || cache_value.expiration <= Utc::now() {
+      return false;
+    }
+    let stored = match cache_value.credentials.as_ref() {
+      Some(stored) if !stored.username.is_empty() && !stored.password.is_empty() => stored,
+      _ => return false,
+    };
+    if !bool::from(stored.username.as_bytes().ct_eq(credentials.username.as_bytes())) {
+      return false;
+    }
+    if is_hashed(&stored.password) {
+      warn!(
+        "digest authentication attempted for order {}, which stores a hashed password",
+        cache_value.order_id
+      );
+      return false;
+    }
+    match credentials.expected_response(&stored.password, method) {
+      Some(expected) => expected.as_bytes().ct_eq(credentials.response.as_bytes()).into(),
+      None => false,
+    }
+  }
+
+  fn check_whitelist(&self, cache_value: Arc<AuthCacheValue>, client_addr: SocketAddr) -> bool {
+    !cache_value.use_credentials && cache_value.is_whitelisted(client_addr.ip()) && cache_value.expiration > Utc::now()
+  }
+}
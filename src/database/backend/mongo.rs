@@ -0,0 +1,191 @@
+use async_trait::async_trait;
+use moka::future::Cache;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use std::{
+  collections::HashMap,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::{
+  cache::{
+    auth::{AuthCache, AuthCacheValue},
+    session::SessionRegistry,
+    usage::BandwidthRecord,
+  },
+  database::{
+    assign_credentials,
+    event_manager::EventHealth,
+    find_order_stock,
+    models::{Stock, UsageRecord, UserOrder},
+  },
+};
+
+use super::{error::AuthBackendError, AuthBackend};
+
+// While change streams are down, cached entries are refreshed in the background at most this often.
+const STALE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const STALE_REFRESH_MAX_KEYS: u64 = 10_000;
+
+#[derive(Clone)]
+pub struct MongoAuthBackend {
+  cache: AuthCache,
+  sessions: SessionRegistry,
+  health: EventHealth,
+  // Addresses with a recent or in-flight background refresh.
+  refreshing: Cache<String, ()>,
+  orders: mongodb::Collection<UserOrder>,
+  stock: mongodb::Collection<Stock>,
+  usage: mongodb::Collection<UsageRecord>,
+}
+
+impl MongoAuthBackend {
+  pub async fn new(client: &mongodb::Client, database: &str, cache: AuthCache, sessions: SessionRegistry, health: EventHealth) -> Self {
+    let db = client.database(database);
+    Self {
+      cache,
+      sessions,
+      health,
+      refreshing: Cache::builder()
+        .max_capacity(STALE_REFRESH_MAX_KEYS)
+        .time_to_live(STALE_REFRESH_INTERVAL)
+        .build(),
+      orders: db.collection("orders"),
+      stock: db.collection("stock"),
+      usage: db.collection("usage"),
+    }
+  }
+}
+
+impl MongoAuthBackend {
+  /// Fetches the order assigned to the address and caches every address of the order,
+  /// addresses without order are negatively cached. Live sessions follow the fetched state, which may have changed
+  /// while the change streams were down.
+  async fn fetch(&self, key: &str) -> Result<Option<Arc<AuthCacheValue>>, mongodb::error::Error> {
+    let order = match self.stock.find_one(doc! { "address": key }, None).await? {
+      Some(stock) => self.orders.find_one(doc! { "_id": stock.used_in_order }, None).await?,
+      None => None,
+    };
+    let order = match order {
+      Some(order) => order,
+      None => {
+        self.release(key).await;
+        return Ok(None);
+      }
+    };
+
+    // A failed lookup must not be mistaken for an unassigned address and negatively cached.
+    let stock_list = find_order_stock(&self.stock, order._id).await?;
+    let mut cv: Option<Arc<AuthCacheValue>> = None;
+    for (proxy, pos) in assign_credentials(&order, &stock_list) {
+      let value = self.cache.insert(proxy, order.clone(), pos).await;
+      if let Ok(ip) = proxy.parse::<IpAddr>() {
+        self.sessions.revalidate_address(ip, &value);
+      }
+      // Save auth cache result to return it at the end of the function.
+      if key == proxy {
+        cv = Some(value);
+      }
+    }
+    if cv.is_none() {
+      self.release(key).await;
+    }
+    Ok(cv)
+  }
+
+  /// Records the address as having no order, its sessions are over.
+  async fn release(&self, key: &str) {
+    self.cache.insert_negative(key).await;
+    if let Ok(ip) = key.parse::<IpAddr>() {
+      self.sessions.terminate_address(ip);
+    }
+  }
+
+  /// Refetches the address off the connection path, unless it has already been refreshed recently.
+  async fn refresh_in_background(&self, key: &str) {
+    if !self.refreshing.entry_by_ref(key).or_insert(()).await.is_fresh() {
+      return;
+    }
+    let (backend, key) = (self.clone(), key.to_string());
+    tokio::spawn(async move {
+      if let Err(e) = backend.fetch(&key).await {
+        warn!("failed to refresh order of {}, keeping cached entry. Err = {}", key, e);
+      }
+    });
+  }
+}
+
+#[async_trait]
+impl AuthBackend for MongoAuthBackend {
+  async fn lookup(&self, proxy_addr: &SocketAddr) -> Option<Arc<AuthCacheValue>> {
+    debug!("get_or_fetch_and_insert {} from cache", proxy_addr);
+    let key = proxy_addr.ip().to_string();
+    if self.cache.is_negative(&key) {
+      return None;
+    }
+
+    if !self.health.is_healthy() {
+      // Cache invalidations are not received while a change stream is down. The cached entry keeps being served,
+      // so a database outage does not hold every handshake, and is refreshed in the background.
+      if let Some(cv) = self.cache.get(&key) {
+        debug!("change streams unhealthy, refreshing {} in background", proxy_addr);
+        self.refresh_in_background(&key).await;
+        return Some(cv);
+      }
+    }
+
+    // Concurrent connections to an uncached address share a single fetch.
+    let fetch = async {
+      self.fetch(&key).await.unwrap_or_else(|e| {
+        warn!("failed to fetch order of {}. Err = {}", proxy_addr, e);
+        None
+      })
+    };
+    self.cache.get_or_load(&key, fetch).await
+  }
+
+  async fn flush_requests(&self, requests: &mut HashMap<String, u64>) -> Result<(), AuthBackendError> {
+    let order_ids: Vec<String> = requests.keys().cloned().collect();
+    for order_id in order_ids {
+      let count = requests[&order_id];
+      let oid = match ObjectId::parse_str(&order_id) {
+        Ok(oid) => oid,
+        Err(_) => {
+          // Never going to succeed, drop it instead of retrying forever.
+          requests.remove(&order_id);
+          return Err(AuthBackendError::InvalidOrderId(order_id));
+        }
+      };
+      self
+        .orders
+        .update_one(doc! { "_id": oid }, doc! { "$inc": { "counters.requests_usage": count as i64 } }, None)
+        .await
+        .map_err(AuthBackendError::MongoDB)?;
+      requests.remove(&order_id);
+    }
+    Ok(())
+  }
+
+  async fn flush_bandwidth(&self, records: &mut Vec<BandwidthRecord>) -> Result<(), AuthBackendError> {
+    let mut docs = Vec::with_capacity(records.len());
+    for record in records.iter() {
+      match ObjectId::parse_str(&record.order_id) {
+        Ok(order_id) => docs.push(UsageRecord {
+          order_id,
+          address: record.address.to_string(),
+          bytes_in: record.bytes_in,
+          bytes_out: record.bytes_out,
+          start: DateTime::from_system_time(record.start.into()),
+          end: DateTime::from_system_time(record.end.into()),
+        }),
+        Err(_) => warn!("dropping bandwidth record with invalid order id {}", record.order_id),
+      }
+    }
+    if !docs.is_empty() {
+      self.usage.insert_many(docs, None).await.map_err(AuthBackendError::MongoDB)?;
+    }
+    records.clear();
+    Ok(())
+  }
+}
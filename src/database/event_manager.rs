@@ -1,25 +1,116 @@
+use std::{
+  net::IpAddr,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
 use mongodb::{
-  bson::doc,
-  change_stream::event::OperationType,
+  bson::{doc, from_document, DateTime, Document},
+  change_stream::{
+    event::{ChangeStreamEvent, OperationType, ResumeToken},
+    ChangeStream,
+  },
+  error::{Error, ErrorKind},
   options::{ChangeStreamOptions, FullDocumentBeforeChangeType, FullDocumentType},
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio_stream::StreamExt;
 
-use crate::cache::auth::AuthCache;
+use crate::cache::{auth::AuthCache, session::SessionRegistry};
+
+use super::{
+  assign_credentials, find_order_stock,
+  models::{Stock, UserOrder},
+};
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// Server error codes meaning the stream cannot be resumed from the token: InvalidResumeToken,
+// ChangeStreamFatalError and ChangeStreamHistoryLost.
+const RESUME_TOKEN_INVALID_CODES: [i32; 3] = [260, 280, 286];
+
+#[derive(Clone, Copy)]
+enum WatchedCollection {
+  Orders,
+  Stock,
+}
+
+impl WatchedCollection {
+  fn name(self) -> &'static str {
+    match self {
+      WatchedCollection::Orders => "orders",
+      WatchedCollection::Stock => "stock",
+    }
+  }
+
+  fn health_bit(self) -> u8 {
+    match self {
+      WatchedCollection::Orders => 1,
+      WatchedCollection::Stock => 2,
+    }
+  }
+}
 
-use super::models::{Stock, UserOrder};
+/// Whether every change stream is currently open, i.e. the auth cache is being kept up to date.
+#[derive(Clone, Default)]
+pub struct EventHealth {
+  // Bitmask of the open change streams.
+  streams: Arc<AtomicU8>,
+}
+
+impl EventHealth {
+  const ALL_STREAMS: u8 = 3;
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn is_healthy(&self) -> bool {
+    self.streams.load(Ordering::Relaxed) == Self::ALL_STREAMS
+  }
+
+  fn set(&self, collection: WatchedCollection, open: bool) {
+    if open {
+      self.streams.fetch_or(collection.health_bit(), Ordering::Relaxed);
+    } else {
+      self.streams.fetch_and(!collection.health_bit(), Ordering::Relaxed);
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedResumeToken {
+  token: ResumeToken,
+}
 
 pub struct DBEventManager {
   cache: AuthCache,
+  sessions: SessionRegistry,
+  health: EventHealth,
+  resume_token_path: Option<PathBuf>,
   orders: mongodb::Collection<UserOrder>,
   stock: mongodb::Collection<Stock>,
 }
 
 impl DBEventManager {
-  pub async fn new(client: &mongodb::Client, database: &str, cache: AuthCache) -> Self {
+  pub async fn new(
+    client: &mongodb::Client,
+    database: &str,
+    cache: AuthCache,
+    sessions: SessionRegistry,
+    health: EventHealth,
+    resume_token_path: Option<String>,
+  ) -> Self {
     let db = client.database(database);
     Self {
       cache,
+      sessions,
+      health,
+      resume_token_path: resume_token_path.map(PathBuf::from),
       orders: db.collection("orders"),
       stock: db.collection("stock"),
     }
@@ -27,18 +118,181 @@ impl DBEventManager {
 
   pub async fn monitor(&self) {
     info!("Started MongoDB EventManager");
-    let cs_opts = ChangeStreamOptions::builder()
+    tokio::join!(self.watch(WatchedCollection::Orders), self.watch(WatchedCollection::Stock));
+  }
+
+  fn change_stream_options(resume_after: Option<ResumeToken>) -> ChangeStreamOptions {
+    ChangeStreamOptions::builder()
       .full_document(Some(FullDocumentType::UpdateLookup))
       .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
-      .build();
-
-    let mut change_stream = self.orders.watch(None, cs_opts).await.unwrap();
-    while let Some(event) = change_stream.next().await.transpose().unwrap() {
-      match event.operation_type {
-        OperationType::Update => self.handle_update(event.full_document).await,
-        OperationType::Insert => self.handle_insert(event.full_document).await,
-        OperationType::Delete => self.handle_delete(event.full_document_before_change).await,
-        _ => (),
+      .resume_after(resume_after)
+      .build()
+  }
+
+  /// Keeps the change stream of the collection open, resuming it after errors from the last processed event.
+  async fn watch(&self, collection: WatchedCollection) {
+    let mut resume_token = self.load_resume_token(collection).await;
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let mut resync = false;
+    loop {
+      let error = match self.open_stream(collection, resume_token.clone()).await {
+        Ok(mut change_stream) => {
+          info!("{} change stream opened", collection.name());
+          if std::mem::take(&mut resync) {
+            self.revalidate_sessions().await;
+          }
+          self.health.set(collection, true);
+          let opened = Instant::now();
+          let error = self.process_stream(collection, &mut change_stream, &mut resume_token).await;
+          self.health.set(collection, false);
+          // Streams failing right after opening keep backing off, only a stream which held up starts over.
+          if opened.elapsed() >= RECONNECT_BACKOFF_MAX {
+            backoff = RECONNECT_BACKOFF_MIN;
+          }
+          error
+        }
+        Err(e) => Some(e),
+      };
+
+      match error {
+        Some(e) if Self::is_resume_token_invalid(&e) => {
+          warn!("{} change stream cannot be resumed, resyncing cache. Err = {}", collection.name(), e);
+          resume_token = None;
+          self.delete_resume_token(collection).await;
+          // Events have been missed, every cached order and live session may be stale.
+          self.cache.invalidate_all();
+          resync = true;
+          info!("{} change stream resyncing in {:?}", collection.name(), backoff);
+        }
+        Some(e) => warn!("{} change stream failed, reconnecting in {:?}. Err = {}", collection.name(), backoff, e),
+        None => warn!("{} change stream closed, reconnecting in {:?}", collection.name(), backoff),
+      }
+
+      tokio::time::sleep(backoff).await;
+      backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+  }
+
+  async fn open_stream(&self, collection: WatchedCollection, resume_token: Option<ResumeToken>) -> Result<ChangeStream<ChangeStreamEvent<Document>>, Error> {
+    let options = DBEventManager::change_stream_options(resume_token);
+    match collection {
+      WatchedCollection::Orders => self.orders.clone_with_type::<Document>().watch(None, options).await,
+      WatchedCollection::Stock => self.stock.clone_with_type::<Document>().watch(None, options).await,
+    }
+  }
+
+  /// Dispatches the stream events until it fails, returning the error if any.
+  async fn process_stream(
+    &self,
+    collection: WatchedCollection,
+    change_stream: &mut ChangeStream<ChangeStreamEvent<Document>>,
+    resume_token: &mut Option<ResumeToken>,
+  ) -> Option<Error> {
+    while let Some(event) = change_stream.next().await {
+      match event {
+        Ok(event) => match collection {
+          WatchedCollection::Orders => self.handle_order_event(event).await,
+          WatchedCollection::Stock => self.handle_stock_event(event).await,
+        },
+        Err(e) => {
+          // Idle streams still advance their post-batch token, keep it to resume as close as possible.
+          *resume_token = change_stream.resume_token().or(resume_token.take());
+          return Some(e);
+        }
+      }
+      *resume_token = change_stream.resume_token();
+      self.save_resume_token(collection, resume_token.as_ref()).await;
+    }
+    *resume_token = change_stream.resume_token().or(resume_token.take());
+    None
+  }
+
+  fn is_resume_token_invalid(error: &Error) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(e) if RESUME_TOKEN_INVALID_CODES.contains(&e.code))
+  }
+
+  fn resume_token_file(&self, collection: WatchedCollection) -> Option<PathBuf> {
+    self
+      .resume_token_path
+      .as_ref()
+      .map(|dir| dir.join(format!("{}.resume_token", collection.name())))
+  }
+
+  async fn load_resume_token(&self, collection: WatchedCollection) -> Option<ResumeToken> {
+    let path = self.resume_token_file(collection)?;
+    let bytes = match tokio::fs::read(&path).await {
+      Ok(bytes) => bytes,
+      Err(e) => {
+        debug!("no resume token loaded from {}. Err = {}", path.display(), e);
+        return None;
+      }
+    };
+    match mongodb::bson::from_slice::<PersistedResumeToken>(&bytes) {
+      Ok(persisted) => {
+        info!("{} change stream resuming from {}", collection.name(), path.display());
+        Some(persisted.token)
+      }
+      Err(e) => {
+        warn!("invalid resume token in {}, ignoring it. Err = {}", path.display(), e);
+        None
+      }
+    }
+  }
+
+  async fn save_resume_token(&self, collection: WatchedCollection, token: Option<&ResumeToken>) {
+    let (path, token) = match (self.resume_token_file(collection), token) {
+      (Some(path), Some(token)) => (path, token),
+      _ => return,
+    };
+    let bytes = match mongodb::bson::to_vec(&PersistedResumeToken { token: token.clone() }) {
+      Ok(bytes) => bytes,
+      Err(e) => return warn!("failed to serialize resume token. Err = {}", e),
+    };
+    if let Err(e) = tokio::fs::write(&path, bytes).await {
+      warn!("failed to persist resume token to {}. Err = {}", path.display(), e);
+    }
+  }
+
+  async fn delete_resume_token(&self, collection: WatchedCollection) {
+    if let Some(path) = self.resume_token_file(collection) {
+      let _ = tokio::fs::remove_file(path).await;
+    }
+  }
+
+  async fn handle_order_event(&self, event: ChangeStreamEvent<Document>) {
+    match event.operation_type {
+      OperationType::Update => self.handle_update(Self::parse_document(event.full_document)).await,
+      OperationType::Insert => self.handle_insert(Self::parse_document(event.full_document)).await,
+      OperationType::Delete => {
+        self
+          .handle_delete(Self::parse_document(event.full_document_before_change), event.document_key)
+          .await
+      }
+      _ => (),
+    }
+  }
+
+  async fn handle_stock_event(&self, event: ChangeStreamEvent<Document>) {
+    match event.operation_type {
+      OperationType::Insert | OperationType::Update | OperationType::Replace => {
+        self
+          .handle_stock_change(
+            Self::parse_document(event.full_document_before_change),
+            Self::parse_document(event.full_document),
+          )
+          .await
+      }
+      OperationType::Delete => self.handle_stock_change(Self::parse_document(event.full_document_before_change), None).await,
+      _ => (),
+    }
+  }
+
+  fn parse_document<T: DeserializeOwned>(doc: Option<Document>) -> Option<T> {
+    match from_document(doc?) {
+      Ok(doc) => Some(doc),
+      Err(e) => {
+        warn!("failed to parse change stream document. Err = {}", e);
+        None
       }
     }
   }
@@ -46,18 +300,8 @@ impl DBEventManager {
   async fn handle_update(&self, doc: Option<UserOrder>) {
     if let Some(doc) = doc {
       debug!("handle_update called on document {}", doc._id);
-      if doc.product_slug == "isp" {
-        let addrs = self.get_order_proxies(&doc).await;
-        // Check if order has multi-credentials.
-        let multi_credentials = doc.proxy.username.len() != 1 && doc.proxy.password.len() != 1;
-        for (i, addr) in addrs.iter().enumerate() {
-          let mut pos = 0;
-          if multi_credentials {
-            pos = i;
-          }
-          self.cache.insert(addr.as_str(), doc.clone(), pos).await;
-        }
-      }
+      // Every product is served from the cache, sessions of any of them must follow the order changes.
+      self.cache_order(&doc).await;
     } else {
       debug!("handle_update called on None");
     }
@@ -66,30 +310,32 @@ impl DBEventManager {
   async fn handle_insert(&self, doc: Option<UserOrder>) {
     if let Some(doc) = doc {
       debug!("handle_insert called on document {}", doc._id);
-      if doc.product_slug == "isp" {
-        let addrs = self.get_order_proxies(&doc).await;
-        // Check if order has multi-credentials.
-        let multi_credentials = doc.proxy.username.len() != 1 && doc.proxy.password.len() != 1;
-        for (i, addr) in addrs.iter().enumerate() {
-          let mut pos = 0;
-          if multi_credentials {
-            pos = i;
-          }
-          self.cache.insert(addr.as_str(), doc.clone(), pos).await;
-        }
-      }
+      self.cache_order(&doc).await;
     } else {
       debug!("handle_insert called on None");
     }
   }
 
-  async fn handle_delete(&self, doc: Option<UserOrder>) {
+  async fn handle_delete(&self, doc: Option<UserOrder>, document_key: Option<Document>) {
+    // The pre-image is only available if enabled on the collection, the key always identifies the order.
+    if let Some(Ok(order_id)) = document_key.as_ref().map(|key| key.get_object_id("_id")) {
+      self.sessions.terminate_order(&order_id.to_hex());
+    }
+
     if let Some(doc) = doc {
       debug!("handle_delete called on document {}", doc._id);
-      if doc.product_slug == "isp" {
-        let addrs = self.get_order_proxies(&doc).await;
-        for addr in addrs.iter() {
-          self.cache.delete(addr.as_str()).await;
+      let stock_list = match find_order_stock(&self.stock, doc._id).await {
+        Ok(stock_list) => stock_list,
+        Err(e) => {
+          // Its addresses are unknown, drop every cached order rather than keep serving the deleted one.
+          warn!("failed to find stock of deleted order {}, invalidating cache. Err = {}", doc._id, e);
+          return self.cache.invalidate_all();
+        }
+      };
+      for stock in stock_list {
+        self.cache.delete(&stock.address).await;
+        if let Ok(ip) = stock.address.parse::<IpAddr>() {
+          self.sessions.terminate_address(ip);
         }
       }
     } else {
@@ -97,19 +343,74 @@ impl DBEventManager {
     }
   }
 
-  async fn get_order_proxies(&self, order: &UserOrder) -> Vec<String> {
-    let mut addrs: Vec<String> = Vec::new();
-    let filter = doc! {
-      "used_in_order": order._id,
+  async fn cache_order(&self, doc: &UserOrder) {
+    let stock_list = match find_order_stock(&self.stock, doc._id).await {
+      Ok(stock_list) => stock_list,
+      Err(e) => return warn!("failed to find stock of order {}, cache not refreshed. Err = {}", doc._id, e),
     };
-    if let Ok(mut docs) = self.stock.find(filter, None).await {
-      while let Some(result) = docs.next().await {
-        match result {
-          Ok(entry) => addrs.push(entry.address),
-          _ => (),
-        }
+    for (addr, pos) in assign_credentials(doc, &stock_list) {
+      let value = self.cache.insert(addr, doc.clone(), pos).await;
+      // Live sessions must not outlive a credentials rotation or an expiration.
+      if let Ok(ip) = addr.parse::<IpAddr>() {
+        self.sessions.revalidate_address(ip, &value);
+      }
+    }
+  }
+
+  async fn handle_stock_change(&self, before: Option<Stock>, after: Option<Stock>) {
+    if before.is_none() && after.is_none() {
+      // Deleted without pre-image, the address is unknown and expires with the cache TTL.
+      return warn!("stock change without document, enable changeStreamPreAndPostImages on the stock collection");
+    }
+
+    // The address itself may have been reassigned, release the previous one.
+    if let Some(before) = &before {
+      if after.as_ref().is_none_or(|after| after.address != before.address) {
+        debug!("stock address {} released", before.address);
+        self.release_address(&before.address).await;
+      }
+    }
+
+    let stock = match after {
+      Some(stock) => stock,
+      None => return,
+    };
+    debug!("handle_stock_change called on address {}", stock.address);
+
+    let expired = stock.used_until.is_some_and(|until| until <= DateTime::now());
+    let order_id = match stock.used_in_order {
+      Some(order_id) if !expired => order_id,
+      _ => return self.release_address(&stock.address).await,
+    };
+
+    // Re-fetch the order so the address is cached with its current credentials assignment.
+    match self.orders.find_one(doc! { "_id": order_id }, None).await {
+      Ok(Some(order)) => self.cache_order(&order).await,
+      Ok(None) => self.release_address(&stock.address).await,
+      Err(e) => {
+        warn!("failed to fetch order {} for stock {}. Err = {}", order_id, stock.address, e);
+        self.release_address(&stock.address).await;
       }
     }
-    return addrs;
+  }
+
+  /// Checks the sessions of every egress address against the current stock and orders, once events have been missed.
+  /// Stream events received meanwhile keep being applied on top.
+  async fn revalidate_sessions(&self) {
+    for ip in self.sessions.addresses() {
+      let addr = ip.to_string();
+      match self.stock.find_one(doc! { "address": &addr }, None).await {
+        Ok(Some(stock)) => self.handle_stock_change(None, Some(stock)).await,
+        Ok(None) => self.release_address(&addr).await,
+        Err(e) => warn!("failed to revalidate sessions on {}. Err = {}", addr, e),
+      }
+    }
+  }
+
+  async fn release_address(&self, addr: &str) {
+    self.cache.delete(addr).await;
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+      self.sessions.terminate_address(ip);
+    }
   }
 }
@@ -1,6 +1,15 @@
-use mongodb::{options::ClientOptions, Client};
+use mongodb::{
+  bson::{doc, oid::ObjectId},
+  error::ErrorKind,
+  options::{ClientOptions, FindOptions},
+  Client, Collection,
+};
+use tokio_stream::StreamExt;
+
+use self::models::{Stock, UserOrder};
 
 pub mod auth_manager;
+pub mod backend;
 pub mod event_manager;
 pub mod models;
 
@@ -8,3 +17,46 @@ pub async fn initialize_client(uri: String) -> Result<Client, mongodb::error::Er
   let client_options = ClientOptions::parse_async(uri).await?;
   Client::with_options(client_options)
 }
+
+/// Stock entries assigned to the order, sorted by insertion so the legacy credentials positions stay stable.
+/// Undecodable entries are skipped, any other error fails the whole lookup.
+pub async fn find_order_stock(stock: &Collection<Stock>, order_id: ObjectId) -> Result<Vec<Stock>, mongodb::error::Error> {
+  let mut entries: Vec<Stock> = Vec::new();
+  let filter = doc! {
+    "used_in_order": order_id,
+  };
+  let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+  let mut docs = stock.find(filter, options).await?;
+  while let Some(result) = docs.next().await {
+    match result {
+      Ok(entry) => entries.push(entry),
+      Err(e) if matches!(e.kind.as_ref(), ErrorKind::BsonDeserialization(_)) => {
+        warn!("failed to read stock entry of order {}. Err = {}", order_id, e)
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(entries)
+}
+
+/// Pairs each stock address of the order with its credentials position, skipping invalid assignments.
+pub fn assign_credentials<'a>(order: &UserOrder, stock: &'a [Stock]) -> Vec<(&'a str, usize)> {
+  let multi_credentials = order.multi_credentials();
+  if multi_credentials && order.proxy.username.len() != stock.len() {
+    warn!(
+      "order {} has {} credentials for {} addresses",
+      order._id,
+      order.proxy.username.len(),
+      stock.len()
+    );
+  }
+
+  let mut assignments = Vec::with_capacity(stock.len());
+  for (i, entry) in stock.iter().enumerate() {
+    match order.credentials_position(entry, i) {
+      Ok(pos) => assignments.push((entry.address.as_str(), pos)),
+      Err(e) => error!("skipping {} of order {}. Err = {}", entry.address, order._id, e),
+    }
+  }
+  assignments
+}
@@ -1,6 +1,10 @@
 use mongodb::bson::{oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
 
+use crate::utils::header_rule::HeaderRule;
+
+use super::backend::error::AuthBackendError;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserOrder {
   pub _id: ObjectId,
@@ -26,6 +30,12 @@ pub struct UserOrderProxy {
   pub password: Vec<String>,
   pub whitelist: Vec<String>,
   pub use_credentials: bool,
+  /// Bearer API tokens, plaintext or hashed like passwords.
+  #[serde(default)]
+  pub tokens: Vec<String>,
+  /// Request header rewrites on plain HTTP traffic.
+  #[serde(default)]
+  pub header_rules: Vec<HeaderRule>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,4 +47,58 @@ pub struct Stock {
   pub used_by: Option<String>,
   pub used_in_order: Option<ObjectId>,
   pub used_until: Option<DateTime>,
+  /// Position of the order credentials assigned to this address, required for multi-credential orders.
+  #[serde(default)]
+  pub credentials_index: Option<u32>,
+}
+
+impl UserOrder {
+  pub fn multi_credentials(&self) -> bool {
+    self.proxy.username.len() > 1
+  }
+
+  /// Resolves the credentials position for a stock address of the order, `legacy_pos` is the address enumeration
+  /// index and is only honoured for multi-credential stock entries without an explicit `credentials_index`.
+  pub fn credentials_position(&self, stock: &Stock, legacy_pos: usize) -> Result<usize, AuthBackendError> {
+    if self.proxy.username.len() != self.proxy.password.len() {
+      return Err(AuthBackendError::CredentialsMismatch(
+        self._id,
+        self.proxy.username.len(),
+        self.proxy.password.len(),
+      ));
+    }
+    if !self.multi_credentials() {
+      return Ok(0);
+    }
+
+    let pos = match stock.credentials_index {
+      Some(index) => index as usize,
+      None => {
+        warn!(
+          "stock {} of multi-credential order {} has no credentials_index, falling back to position {}",
+          stock.address, self._id, legacy_pos
+        );
+        legacy_pos
+      }
+    };
+
+    if pos >= self.proxy.username.len() {
+      return Err(AuthBackendError::CredentialsIndexOutOfRange(
+        stock.address.clone(),
+        pos,
+        self.proxy.username.len(),
+      ));
+    }
+    Ok(pos)
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageRecord {
+  pub order_id: ObjectId,
+  pub address: String,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+  pub start: DateTime,
+  pub end: DateTime,
 }
@@ -3,14 +3,20 @@ use std::{
   sync::Arc,
 };
 
-use cache::auth::AuthCache;
-use database::{auth_manager::AuthManager, event_manager::DBEventManager, initialize_client};
+use cache::{auth::AuthCache, session::SessionRegistry};
+use database::{
+  auth_manager::AuthManager,
+  backend::{file::FileAuthBackend, mongo::MongoAuthBackend},
+  event_manager::{DBEventManager, EventHealth},
+  initialize_client,
+};
 use dns::DnsResolver;
 use proxy::Proxy;
 use tokio::sync::{Barrier, Semaphore};
 use utils::{
-  config::{load_config, parse_args, ProxyConfig},
+  config::{load_config, parse_args, AuthBackendType, ProxyConfig},
   socket::make_subnet_vec,
+  tls::load_acceptor,
 };
 
 #[macro_use]
@@ -28,23 +34,78 @@ async fn main() {
   let config = load_config(config_path).expect("Error parsing config.toml file");
   log4rs::init_file(config.log4rs.location, Default::default()).expect("Failed to initialize log4rs");
 
-  let client = initialize_client(config.mongodb.uri).await.expect("Failed to initialize MongoDB Client");
+  let sessions = SessionRegistry::new();
+
+  let (auth_manager, event_manager) = match config.auth.backend {
+    AuthBackendType::MongoDB => {
+      let mongodb = config.mongodb.expect("Missing [mongodb] config section, required by the mongodb auth backend");
+      let client = initialize_client(mongodb.uri).await.expect("Failed to initialize MongoDB Client");
+
+      let auth_cache = AuthCache::new(config.cache.auth);
+      let health = EventHealth::new();
+      let backend = MongoAuthBackend::new(&client, &mongodb.database, auth_cache.clone(), sessions.clone(), health.clone()).await;
+      let event_manager = DBEventManager::new(
+        &client,
+        &mongodb.database,
+        auth_cache.clone(),
+        sessions.clone(),
+        health,
+        mongodb.resume_token_path,
+      )
+      .await;
 
-  let auth_cache = AuthCache::new(config.cache.auth);
-  let auth_manager = AuthManager::new(&client, &config.mongodb.database, auth_cache.clone()).await;
-  let event_manager = DBEventManager::new(&client, &config.mongodb.database, auth_cache.clone()).await;
+      (
+        AuthManager::new(Arc::new(backend), config.usage, sessions, config.cache.credentials, config.cache.nonces),
+        Some(event_manager),
+      )
+    }
+    AuthBackendType::File => {
+      let path = config.auth.path.expect("Missing auth.path option, required by the file auth backend");
+      let backend = FileAuthBackend::load(&path).expect("Error parsing file auth backend source");
+
+      (
+        AuthManager::new(Arc::new(backend), config.usage, sessions, config.cache.credentials, config.cache.nonces),
+        None,
+      )
+    }
+  };
 
   let dns_resolver = DnsResolver::new(config.cache.dns);
 
-  tokio::join!(event_manager.monitor(), handle_preload(config.proxy, auth_manager, dns_resolver),);
+  tokio::join!(
+    monitor_events(event_manager),
+    auth_manager.flush_usage(),
+    handle_preload(config.proxy, auth_manager.clone(), dns_resolver),
+  );
+}
+
+async fn monitor_events(event_manager: Option<DBEventManager>) {
+  if let Some(event_manager) = event_manager {
+    event_manager.monitor().await;
+  }
 }
 
 async fn handle_preload(config: ProxyConfig, auth_manager: AuthManager, dns_resolver: DnsResolver) {
   let preload = config.clone().preload;
 
   let semaphore = Arc::new(Semaphore::new(preload.tasks)); // Limit sockets binding concurrency to 100
-  let addrs = preload.addrs.unwrap_or(Vec::new());
-  let subnets_addrs: Vec<Ipv4Addr> = preload.subnets.unwrap_or(Vec::new()).iter().flat_map(|&sub| make_subnet_vec(sub)).collect();
+  let addrs = preload.addrs.unwrap_or_default();
+  let subnets_addrs: Vec<Ipv4Addr> = preload.subnets.unwrap_or_default().iter().flat_map(|&sub| make_subnet_vec(sub)).collect();
+
+  // Loaded once, shared by the HTTPS listeners of every address.
+  let tls = config.ports.https.map(|_| {
+    let cert_path = config
+      .ports
+      .tls_cert
+      .as_deref()
+      .expect("Missing proxy.ports.tls_cert option, required by ports.https");
+    let key_path = config
+      .ports
+      .tls_key
+      .as_deref()
+      .expect("Missing proxy.ports.tls_key option, required by ports.https");
+    load_acceptor(cert_path, key_path, config.ports.tls_http2).expect("Failed to load the HTTPS proxy certificate")
+  });
 
   let barrier = Arc::new(Barrier::new(addrs.len() + subnets_addrs.len()));
 
@@ -60,8 +121,9 @@ async fn handle_preload(config: ProxyConfig, auth_manager: AuthManager, dns_reso
     let auth_manager = auth_manager.clone();
     let dns_resolver = dns_resolver.clone();
     let config = config.clone();
+    let tls = tls.clone();
     tokio::spawn(async move {
-      Proxy::new(barrier, semaphore, config, IpAddr::V4(addr), auth_manager, dns_resolver)
+      Proxy::new(barrier, semaphore, config, IpAddr::V4(addr), auth_manager, dns_resolver, tls)
         .listen()
         .await;
     });
@@ -73,8 +135,9 @@ async fn handle_preload(config: ProxyConfig, auth_manager: AuthManager, dns_reso
     let auth_manager = auth_manager.clone();
     let dns_resolver = dns_resolver.clone();
     let config = config.clone();
+    let tls = tls.clone();
     tokio::spawn(async move {
-      Proxy::new(barrier, semaphore, config, IpAddr::V4(addr), auth_manager, dns_resolver)
+      Proxy::new(barrier, semaphore, config, IpAddr::V4(addr), auth_manager, dns_resolver, tls)
         .listen()
         .await;
     });
@@ -0,0 +1,124 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use chrono::Utc;
+
+use crate::{
+  cache::auth::AuthCacheValue,
+  database::auth_manager::{AuthManager, DigestOutcome},
+  utils::{
+    auth::ProxyAuthorization,
+    config::{HttpAuthScheme, ProxyConfig},
+    digest::DigestAlgorithm,
+  },
+};
+
+pub enum AuthFailure {
+  /// Missing or invalid credentials, answered with the challenges of the product.
+  Denied {
+    /// `None` if no order is assigned to the listen address.
+    product: Option<String>,
+    /// The Digest nonce expired, the client may retry with the new one without prompting the user.
+    stale: bool,
+  },
+  /// The order expired, no credentials would be accepted.
+  Expired,
+}
+
+/// Authenticates the requests of a client connection against the order of the listen address, whatever the HTTP version.
+#[derive(Clone)]
+pub struct HttpAuthenticator {
+  auth_manager: AuthManager,
+  config: Arc<ProxyConfig>,
+  listen_addr: SocketAddr,
+  client_addr: SocketAddr,
+}
+
+impl HttpAuthenticator {
+  pub fn new(auth_manager: AuthManager, config: Arc<ProxyConfig>, listen_addr: SocketAddr, client_addr: SocketAddr) -> Self {
+    Self {
+      auth_manager,
+      config,
+      listen_addr,
+      client_addr,
+    }
+  }
+
+  /// `uris` are the forms of the request target the Digest `uri` parameter may be given in.
+  pub async fn authenticate(&self, authentication: Option<&ProxyAuthorization>, method: &str, uris: &[&str]) -> Result<Arc<AuthCacheValue>, AuthFailure> {
+    let cv = match self.auth_manager.get_or_fetch_and_insert(&self.listen_addr).await {
+      Some(cv) => cv,
+      None => return Err(AuthFailure::Denied { product: None, stale: false }),
+    };
+    if cv.expiration <= Utc::now() {
+      return Err(AuthFailure::Expired);
+    }
+    let auth_config = self.config.http.auth(&cv.product);
+    let failure = |stale| AuthFailure::Denied {
+      product: Some(cv.product.clone()),
+      stale,
+    };
+
+    let authorized = if cv.use_credentials {
+      // If credentials are required, check them if provided with an accepted scheme, otherwise deny.
+      match authentication {
+        Some(ProxyAuthorization::Basic(username, password)) if auth_config.schemes.contains(&HttpAuthScheme::Basic) => {
+          self.auth_manager.check_credentials(cv.clone(), username, password).await
+        }
+        Some(ProxyAuthorization::Bearer(token)) if auth_config.schemes.contains(&HttpAuthScheme::Bearer) => {
+          self.auth_manager.check_token(cv.clone(), token).await
+        }
+        Some(ProxyAuthorization::Digest(credentials)) if auth_config.schemes.contains(&HttpAuthScheme::Digest) => {
+          // The response covers the realm and the request target, both must be the ones of this request.
+          if credentials.realm != auth_config.realm || !uris.contains(&credentials.uri.as_str()) {
+            false
+          } else {
+            match self.auth_manager.check_digest(cv.clone(), credentials, method) {
+              DigestOutcome::Valid => true,
+              DigestOutcome::Stale => return Err(failure(true)),
+              DigestOutcome::Invalid => false,
+            }
+          }
+        }
+        _ => false,
+      }
+    } else {
+      // If no credentials required and no credentials are in the request data, check the whitelist.
+      authentication.is_none() && self.auth_manager.check_whitelist(cv.clone(), self.client_addr)
+    };
+
+    if authorized {
+      Ok(cv)
+    } else {
+      Err(failure(false))
+    }
+  }
+
+  /// `Proxy-Authenticate` values, a challenge for every scheme accepted by the product. Addresses without order get
+  /// no Digest challenge, so scanning them cannot fill the nonces cache.
+  pub async fn challenges(&self, product: Option<&str>, stale: bool) -> Vec<String> {
+    let auth_config = self.config.http.auth(product.unwrap_or_default());
+    let mut challenges = Vec::new();
+    for scheme in &auth_config.schemes {
+      match scheme {
+        HttpAuthScheme::Basic => challenges.push(format!("Basic realm=\"{}\"", auth_config.realm)),
+        HttpAuthScheme::Digest if product.is_none() => (),
+        HttpAuthScheme::Digest => {
+          let nonce = self.auth_manager.issue_nonce().await;
+          let stale = if stale { ", stale=true" } else { "" };
+          // Strongest algorithm first, MD5 is kept for older clients.
+          for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Md5] {
+            challenges.push(format!(
+              "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+              auth_config.realm,
+              algorithm.name(),
+              nonce,
+              stale
+            ));
+          }
+        }
+        HttpAuthScheme::Bearer => challenges.push(format!("Bearer realm=\"{}\"", auth_config.realm)),
+      }
+    }
+    challenges
+  }
+}
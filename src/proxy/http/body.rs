@@ -0,0 +1,204 @@
+use bytes::{Buf, BytesMut};
+use httparse::Header;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::utils::error::HttpParserError;
+
+/// How the end of a message body is delimited, see RFC 9112 section 6.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFraming {
+  Empty,
+  ContentLength(u64),
+  Chunked,
+  /// Only used for responses, the body ends when the server closes the connection.
+  UntilClose,
+}
+
+impl BodyFraming {
+  const MAX_LINE: usize = 4096;
+
+  /// Reads the framing declared by the message headers, `None` if it declares none.
+  pub fn from_headers(headers: &[Header]) -> Result<Option<BodyFraming>, HttpParserError> {
+    // Every Transfer-Encoding field line makes up a single list, chunked must be its final coding.
+    let mut last_coding = None;
+    for header in headers.iter().filter(|header| header.name.eq_ignore_ascii_case("transfer-encoding")) {
+      let coding = header
+        .value
+        .rsplit(|&c| c == b',')
+        .map(|coding| coding.trim_ascii())
+        .find(|coding| !coding.is_empty());
+      last_coding = coding.or(last_coding).or(Some(b""));
+    }
+    if let Some(coding) = last_coding {
+      // Transfer-Encoding takes precedence over Content-Length.
+      return match coding.eq_ignore_ascii_case(b"chunked") {
+        true => Ok(Some(BodyFraming::Chunked)),
+        false => Ok(Some(BodyFraming::UntilClose)),
+      };
+    }
+
+    let mut framing = None;
+    for header in headers.iter().filter(|header| header.name.eq_ignore_ascii_case("content-length")) {
+      let length = std::str::from_utf8(header.value)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .ok_or(HttpParserError::InvalidContentLength)?;
+      if framing.is_some_and(|framing| framing != BodyFraming::ContentLength(length)) {
+        return Err(HttpParserError::InvalidContentLength);
+      }
+      framing = Some(BodyFraming::ContentLength(length));
+    }
+    Ok(framing)
+  }
+
+  /// Whether the whole body is already held by `buffer`, so the request can be sent again.
+  pub fn is_buffered(self, buffer: &[u8]) -> bool {
+    match self {
+      BodyFraming::Empty => true,
+      BodyFraming::ContentLength(length) => buffer.len() as u64 >= length,
+      BodyFraming::Chunked | BodyFraming::UntilClose => false,
+    }
+  }
+
+  /// Relays the body from `src` to `dst`, starting with the bytes already read into `buffer`.
+  /// Bytes read past the end of the body are left in `buffer`.
+  pub async fn forward<R, W>(self, buffer: &mut BytesMut, src: &mut R, dst: &mut W) -> io::Result<()>
+  where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+  {
+    match self {
+      BodyFraming::Empty => (),
+      BodyFraming::ContentLength(length) => Self::forward_exact(length, buffer, src, dst).await?,
+      BodyFraming::Chunked => loop {
+        let line = Self::read_line(buffer, src).await?;
+        dst.write_all(&line).await?;
+        let size = std::str::from_utf8(&line)
+          .ok()
+          .and_then(|line| u64::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+          .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+          // Last chunk, relay the trailer section up to the empty line.
+          loop {
+            let line = Self::read_line(buffer, src).await?;
+            dst.write_all(&line).await?;
+            if line.as_ref() == b"\r\n" || line.as_ref() == b"\n" {
+              break;
+            }
+          }
+          break;
+        }
+        // Chunk data followed by its CRLF.
+        let length = size
+          .checked_add(2)
+          .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        Self::forward_exact(length, buffer, src, dst).await?;
+      },
+      BodyFraming::UntilClose => {
+        dst.write_all(buffer).await?;
+        buffer.clear();
+        tokio::io::copy(src, dst).await?;
+      }
+    }
+    dst.flush().await
+  }
+
+  async fn forward_exact<R, W>(length: u64, buffer: &mut BytesMut, src: &mut R, dst: &mut W) -> io::Result<()>
+  where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+  {
+    let buffered = buffer.len().min(length as usize);
+    dst.write_all(&buffer[..buffered]).await?;
+    buffer.advance(buffered);
+
+    let remaining = length - buffered as u64;
+    if remaining > 0 && tokio::io::copy(&mut src.take(remaining), dst).await? < remaining {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+  }
+
+  async fn read_line<R: AsyncRead + Unpin>(buffer: &mut BytesMut, src: &mut R) -> io::Result<BytesMut> {
+    loop {
+      if let Some(pos) = buffer.iter().position(|&c| c == b'\n') {
+        return Ok(buffer.split_to(pos + 1));
+      }
+      if buffer.len() > Self::MAX_LINE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk line too long"));
+      }
+      if src.read_buf(buffer).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header<'a>(name: &'a str, value: &'a str) -> Header<'a> {
+    Header { name, value: value.as_bytes() }
+  }
+
+  async fn forward(framing: BodyFraming, input: &[u8]) -> (io::Result<()>, Vec<u8>, BytesMut) {
+    let mut buffer = BytesMut::from(input);
+    let mut output = Vec::new();
+    let result = framing.forward(&mut buffer, &mut &b""[..], &mut output).await;
+    (result, output, buffer)
+  }
+
+  #[test]
+  fn duplicate_content_length() {
+    let headers = [header("Content-Length", "5"), header("content-length", " 5 ")];
+    assert_eq!(BodyFraming::from_headers(&headers).unwrap(), Some(BodyFraming::ContentLength(5)));
+  }
+
+  #[test]
+  fn conflicting_content_length() {
+    let headers = [header("Content-Length", "5"), header("Content-Length", "6")];
+    assert!(BodyFraming::from_headers(&headers).is_err());
+    assert!(BodyFraming::from_headers(&[header("Content-Length", "5, 6")]).is_err());
+  }
+
+  #[test]
+  fn chunked_takes_precedence_over_content_length() {
+    let headers = [header("Content-Length", "5"), header("Transfer-Encoding", "chunked")];
+    assert_eq!(BodyFraming::from_headers(&headers).unwrap(), Some(BodyFraming::Chunked));
+  }
+
+  #[test]
+  fn non_final_chunked_coding() {
+    let headers = [header("Transfer-Encoding", "chunked, gzip")];
+    assert_eq!(BodyFraming::from_headers(&headers).unwrap(), Some(BodyFraming::UntilClose));
+    // Field lines make up a single list, the last line holds the final coding.
+    let headers = [header("Transfer-Encoding", "chunked"), header("Transfer-Encoding", "gzip")];
+    assert_eq!(BodyFraming::from_headers(&headers).unwrap(), Some(BodyFraming::UntilClose));
+    let headers = [header("Transfer-Encoding", "gzip"), header("Transfer-Encoding", "chunked")];
+    assert_eq!(BodyFraming::from_headers(&headers).unwrap(), Some(BodyFraming::Chunked));
+  }
+
+  #[tokio::test]
+  async fn chunked_body_ends_at_last_chunk() {
+    let body = b"5;ext=1\r\nhello\r\n0\r\nTrailer: x\r\n\r\n";
+    let (result, output, buffer) = forward(BodyFraming::Chunked, &[&body[..], b"GET / HTTP/1.1"].concat()).await;
+    assert!(result.is_ok());
+    assert_eq!(output, body);
+    assert_eq!(&buffer[..], b"GET / HTTP/1.1");
+  }
+
+  #[tokio::test]
+  async fn overflowing_chunk_size() {
+    let (result, _, _) = forward(BodyFraming::Chunked, b"ffffffffffffffff\r\nhello\r\n").await;
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[tokio::test]
+  async fn truncated_content_length_body() {
+    let (result, output, _) = forward(BodyFraming::ContentLength(10), b"hello").await;
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(output, b"hello");
+  }
+}
@@ -1,126 +1,358 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use bytes::BytesMut;
 use tokio::{
-  io::AsyncWriteExt,
+  io::{AsyncRead, AsyncWrite, AsyncWriteExt},
   net::TcpStream,
-  time::{timeout, Duration},
+  time::{timeout, Instant},
 };
 
-use crate::{database::auth_manager::AuthManager, dns::DnsResolver, utils::socket::make_outbound};
+use crate::{
+  cache::{auth::AuthCacheValue, session::Session},
+  database::auth_manager::AuthManager,
+  dns::DnsResolver,
+  utils::{
+    config::ProxyConfig,
+    idle::{Activity, ActivityStream, RelayTimeout},
+    metered::MeteredStream,
+  },
+};
 
-use super::{parser::HttpParser, utils::constants::HttpResponse};
+use super::{
+  auth::{AuthFailure, HttpAuthenticator},
+  outbound::connect_target,
+  parser::{HttpParser, HttpRequestData, HttpResponseHead},
+  utils::{
+    constants::HttpResponse,
+    error::{ErrorResponse, HttpHandlerError, HttpParserError},
+  },
+};
 
-pub struct HttpHandler<'a> {
-  stream: &'a mut TcpStream,
+/// Serves a client connection, plaintext or decrypted by the HTTPS listener.
+pub struct HttpHandler<'a, S> {
+  stream: &'a mut S,
+  client_addr: SocketAddr,
   listen_addr: SocketAddr,
   auth_manager: AuthManager,
   dns_resolver: DnsResolver,
+  config: Arc<ProxyConfig>,
+  authenticator: HttpAuthenticator,
+  // Traffic on the outbound connections, for the idle timeout.
+  activity: Activity,
+  // End of the connection max lifetime.
+  deadline: Instant,
 }
 
-impl<'a> HttpHandler<'a> {
-  const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+type Outbound = ActivityStream<MeteredStream<TcpStream>>;
+
+/// How the client connection goes on after a relayed exchange.
+enum Exchange {
+  KeepAlive,
+  Close,
+  /// The target switched protocols, the connection is relayed as raw bytes.
+  Upgraded,
+}
 
-  pub fn new(stream: &'a mut TcpStream, listen_addr: SocketAddr, auth_manager: AuthManager, dns_resolver: DnsResolver) -> HttpHandler<'a> {
+/// Connection to the target server, reused by the following requests to the same host.
+struct Upstream {
+  host: (String, u16),
+  stream: Outbound,
+  buffer: BytesMut,
+}
+
+impl Upstream {
+  fn new(host: (String, u16), stream: Outbound) -> Self {
+    Self {
+      host,
+      stream,
+      buffer: BytesMut::new(),
+    }
+  }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> HttpHandler<'a, S> {
+  pub fn new(
+    stream: &'a mut S,
+    client_addr: SocketAddr,
+    listen_addr: SocketAddr,
+    auth_manager: AuthManager,
+    dns_resolver: DnsResolver,
+    config: Arc<ProxyConfig>,
+  ) -> HttpHandler<'a, S> {
+    let deadline = Instant::now() + config.timeouts.max_lifetime;
+    let authenticator = HttpAuthenticator::new(auth_manager.clone(), config.clone(), listen_addr, client_addr);
     HttpHandler {
       stream,
+      client_addr,
       listen_addr,
       auth_manager,
       dns_resolver,
+      config,
+      authenticator,
+      activity: Activity::new(),
+      deadline,
     }
   }
 
   pub async fn execute(&mut self) {
-    let mut req_data = match HttpParser::new(self.stream).read().await {
-      Ok(r) => r,
-      Err(e) => return warn!("{}", e),
-    };
+    let mut parser = HttpParser::new(self.config.http.max_header_size);
+    let mut session: Option<(Arc<AuthCacheValue>, Session)> = None;
+    let mut upstream: Option<Upstream> = None;
 
-    if !self.handle_authentication(&req_data.authentication).await {
-      return self.reply(HttpResponse::ProxyAuthenticationRequired).await;
-    }
+    loop {
+      // The first request completes the handshake, the following ones arrive on an idle keep-alive connection.
+      let head_timeout = if session.is_none() {
+        self.config.timeouts.handshake
+      } else {
+        self.config.timeouts.idle
+      };
+      let head_timeout = head_timeout.min(self.deadline.saturating_duration_since(Instant::now()));
+      let mut req_data = match parser.read(self.stream, head_timeout).await {
+        Ok(r) => r,
+        Err(e) => match ErrorResponse::from_request(&e) {
+          Some(response) => {
+            debug!("{}", e);
+            return self.reply_error(response).await;
+          }
+          None if session.is_some() => return debug!("keep-alive connection ended. {}", e),
+          None => return warn!("{}", e),
+        },
+      };
 
-    // let target_host = format!("{}:{}", req_data.host.0, req_data.host.1);
+      // Every request on the connection is authenticated and accounted on its own.
+      // Some clients send the origin-form of absolute targets in the Digest uri.
+      let origin_form = req_data.convert_absolute_uri_to_path();
+      let uris: Vec<&str> = [Some(req_data.path.as_str()), origin_form.as_deref()].into_iter().flatten().collect();
+      let authentication = self.authenticator.authenticate(req_data.authentication.as_ref(), &req_data.method, &uris).await;
+      let cache_value = match authentication {
+        Ok(cv) => cv,
+        Err(AuthFailure::Expired) => return self.reply_error(ErrorResponse::OrderExpired).await,
+        Err(AuthFailure::Denied { product, stale }) => return self.reply_authentication_required(product.as_deref(), stale).await,
+      };
 
-    let target_addr = match self.dns_resolver.resolve(&req_data.host.0, req_data.host.1).await {
-      Ok(h) => h,
-      Err(e) => {
-        warn!("failed to resolve host {:?}. Err = {}", req_data.host, e);
-        return self.reply(HttpResponse::InternalServerError).await;
+      if !self.auth_manager.try_consume_request(&cache_value) {
+        debug!("requests limit reached for order {}", cache_value.order_id);
+        return self.reply_error(ErrorResponse::RequestsLimitReached).await;
       }
-    };
 
-    // let dns_start_time = Instant::now();
+      debug!("{} {} (Order: {})", req_data.method, req_data.path, cache_value.order_id);
 
-    let bind_addr = SocketAddr::from((self.listen_addr.ip(), 0));
+      // Sessions are bound to an order, the address may have been reassigned since the previous request.
+      if session.as_ref().is_none_or(|(cv, _)| cv.order_id != cache_value.order_id) {
+        upstream = None;
+        session = Some((cache_value.clone(), self.auth_manager.open_session(&cache_value, self.listen_addr.ip())));
+      }
+      let (_, session) = session.as_ref().unwrap();
 
-    let mut outbound = match make_outbound(bind_addr, target_addr).await {
-      Ok(outbound) => {
-        // self.dns_resolver.record_latency(target_addr, dns_start_time.elapsed()).await;
-        outbound
+      if req_data.method == "CONNECT" {
+        return self.tunnel(&mut parser, &req_data, session).await;
       }
-      Err(e) => {
-        warn!("failed to create outbound TcpStream ({:?}). Err = {}", req_data.host, e);
-        return self.reply(HttpResponse::InternalServerError).await;
+
+      let forwarding = self.config.http.forwarding(&cache_value.product);
+      req_data.headers.apply_forwarding(forwarding, self.client_addr.ip(), &req_data.version);
+      req_data.headers.apply_rules(self.config.http.header_rules(&cache_value.product));
+      req_data.headers.apply_rules(&cache_value.header_rules);
+
+      let reused = upstream.as_ref().is_some_and(|upstream| upstream.host == req_data.host);
+      if !reused {
+        upstream = match self.connect(&req_data.host, session).await {
+          Some(stream) => Some(Upstream::new(req_data.host.clone(), stream)),
+          None => return,
+        };
       }
-    };
+      // The body is streamed to the target without waiting for its interim response, the client is told to go on.
+      if req_data.take_expect_continue() {
+        self.reply(HttpResponse::Continue).await;
+      }
+      // The target may have closed a kept-alive connection meanwhile. Requests it never answered are sent once again
+      // on a new connection, if they can safely be, along with their buffered body.
+      let mut replay = (reused && req_data.is_idempotent() && req_data.body_framing.is_buffered(parser.buffer())).then(|| parser.buffer().clone());
+
+      let mut responded;
+      let result = loop {
+        let target = upstream.as_mut().unwrap();
+        responded = false;
+        let (activity, idle, deadline) = (self.activity.clone(), self.config.timeouts.idle, self.deadline);
+        let result = tokio::select! {
+          result = activity.watch(self.exchange(&mut parser, &mut req_data, target, &mut responded), idle, deadline) => result,
+          _ = session.terminated() => return debug!("session terminated for order {} ({:?})", cache_value.order_id, &req_data.host),
+        };
+        let lost = matches!(&result, Ok(Err(e)) if !responded && target.buffer.is_empty() && e.is_connection_lost());
+        match replay.take() {
+          Some(buffer) if lost => {
+            debug!("kept-alive connection closed by the target, sending the request again ({:?})", &req_data.host);
+            *parser.buffer() = buffer;
+            upstream = match self.connect(&req_data.host, session).await {
+              Some(stream) => Some(Upstream::new(req_data.host.clone(), stream)),
+              None => return,
+            };
+          }
+          _ => break result,
+        }
+      };
+      match result {
+        Ok(Ok(Exchange::KeepAlive)) => (),
+        Ok(Ok(Exchange::Close)) => return,
+        Ok(Ok(Exchange::Upgraded)) => {
+          let mut target = upstream.take().unwrap();
+          return self.relay(&mut target.stream, &req_data.host, session).await;
+        }
+        // Never write an error in the middle of a relayed response.
+        Ok(Err(e)) => {
+          warn!("{} ({:?})", e, &req_data.host);
+          if !responded {
+            self.reply_error(ErrorResponse::from_exchange(&e)).await;
+          }
+          return;
+        }
+        Err(e) => {
+          debug!("{} on http exchange ({:?})", e, &req_data.host);
+          if !responded && matches!(e, RelayTimeout::Idle(_)) {
+            self.reply_error(ErrorResponse::TargetTimeout).await;
+          }
+          return;
+        }
+      }
+    }
+  }
 
-    if req_data.method == "CONNECT" {
-      self.reply(HttpResponse::OkEstablished).await;
-    } else {
-      let request = req_data.into_request();
-      self.write(&mut outbound, &req_data.host, &request).await;
+  async fn connect(&mut self, host: &(String, u16), session: &Session) -> Option<Outbound> {
+    match connect_target(&self.dns_resolver, self.listen_addr, host, self.config.timeouts.connect).await {
+      Ok(outbound) => Some(ActivityStream::new(
+        MeteredStream::new(outbound, session.bandwidth.clone()),
+        self.activity.clone(),
+      )),
+      Err(response) => {
+        self.reply_error(response).await;
+        None
+      }
     }
+  }
 
-    if let Err(_) = tokio::time::timeout(HttpHandler::MAX_TIMEOUT, tokio::io::copy_bidirectional(self.stream, &mut outbound)).await {
-      warn!("timeout on copy_bidirectional ({:?})", &req_data.host);
-      return self.reply(HttpResponse::GatewayTimeout).await;
+  /// Relays the CONNECT tunnel until either side closes it, the connection is not parsed afterwards.
+  async fn tunnel(&mut self, parser: &mut HttpParser, req_data: &HttpRequestData, session: &Session) {
+    let mut outbound = match self.connect(&req_data.host, session).await {
+      Some(outbound) => outbound,
+      None => return,
+    };
+
+    self.reply(HttpResponse::OkEstablished).await;
+
+    // The client may not have waited for the reply before sending the tunneled data.
+    let early_data = parser.buffer().split();
+    if !early_data.is_empty() && !self.write(&mut outbound, &req_data.host, &early_data).await {
+      return;
     }
+
+    self.relay(&mut outbound, &req_data.host, session).await;
   }
 
-  async fn handle_authentication(&self, auth_data: &Option<(String, String)>) -> bool {
-    if let Some(cv) = self.auth_manager.get_or_fetch_and_insert(&self.listen_addr).await {
-      if cv.use_credentials {
-        // If credentials are required, check them if provided, otherwise return false.
-        return auth_data
-          .as_ref()
-          .map_or(false, |(username, password)| self.auth_manager.check_credentials(cv, username, password));
-      } else if let Ok(client_addr) = self.stream.peer_addr() {
-        if auth_data.is_none() {
-          // If no credentials required and no credentials are in the request data, check the whitelist.
-          return self.auth_manager.check_whitelist(cv, client_addr);
+  /// Relays raw bytes both ways until either side closes, for CONNECT tunnels and upgraded connections.
+  /// The data is opaque, usually TLS or WebSocket frames, timeouts only close it.
+  async fn relay(&mut self, outbound: &mut Outbound, host: &(String, u16), session: &Session) {
+    let (activity, idle, deadline) = (self.activity.clone(), self.config.timeouts.idle, self.deadline);
+    tokio::select! {
+      result = activity.watch(tokio::io::copy_bidirectional(self.stream, outbound), idle, deadline) => {
+        if let Err(e) = result {
+          debug!("{} on tunnel ({:?})", e, host);
         }
       }
-      // Fall-through case: credentials are not used and client address is not retrieved.
-      false
-    } else {
-      // Case where 'get_or_fetch_and_insert' yields None.
-      false
+      _ = session.terminated() => debug!("session terminated ({:?})", host),
+    }
+  }
+
+  /// Relays one request and its response, returns how the connection goes on.
+  /// `responded` is set once the response head has been written to the client.
+  async fn exchange(
+    &mut self,
+    parser: &mut HttpParser,
+    req_data: &mut HttpRequestData,
+    upstream: &mut Upstream,
+    responded: &mut bool,
+  ) -> Result<Exchange, HttpHandlerError> {
+    upstream.stream.write_all(&req_data.build_request()).await?;
+    req_data
+      .body_framing
+      .forward(parser.buffer(), self.stream, &mut upstream.stream)
+      .await
+      .map_err(HttpHandlerError::Request)?;
+
+    loop {
+      let response = HttpResponseHead::read(&mut upstream.stream, &mut upstream.buffer, &req_data.method).await?;
+      if response.status == 101 && !req_data.is_upgrade() {
+        return Err(HttpParserError::UnexpectedUpgrade.into());
+      }
+      let keep_alive = req_data.keep_alive && response.keep_alive;
+      let connection = if response.status == 101 {
+        Some("upgrade")
+      } else if (100..200).contains(&response.status) {
+        None
+      } else if !keep_alive {
+        Some("close")
+      } else if req_data.version == "1.0" {
+        Some("keep-alive")
+      } else {
+        None
+      };
+      *responded = true;
+      self.stream.write_all(&response.build_response(connection)).await?;
+
+      if response.status == 101 {
+        // Switched protocols, the connection is no longer HTTP. Bytes already read on either side belong to the new protocol.
+        self.stream.write_all(&upstream.buffer.split()).await?;
+        upstream.stream.write_all(&parser.buffer().split()).await?;
+        return Ok(Exchange::Upgraded);
+      }
+      if (100..200).contains(&response.status) {
+        // Interim response, the final one follows.
+        continue;
+      }
+
+      response.body_framing.forward(&mut upstream.buffer, &mut upstream.stream, self.stream).await?;
+      return Ok(if keep_alive { Exchange::KeepAlive } else { Exchange::Close });
+    }
+  }
+
+  /// Answers 407 with a challenge for every scheme accepted by the product.
+  async fn reply_authentication_required(&mut self, product: Option<&str>, stale: bool) {
+    let mut response = BytesMut::from(&b"HTTP/1.1 407 Proxy Authentication Required\r\n"[..]);
+    for challenge in self.authenticator.challenges(product, stale).await {
+      response.extend_from_slice(format!("Proxy-Authenticate: {}\r\n", challenge).as_bytes());
     }
+    response.extend_from_slice(b"X-Lampo-Error: auth-required\r\nConnection: close\r\n\r\nAccess Denied");
+    self.reply_bytes(&response).await;
   }
 
   async fn reply(&mut self, response: HttpResponse) {
-    match tokio::time::timeout(HttpHandler::MAX_TIMEOUT, self.stream.write_all(response.as_bytes())).await {
+    self.reply_bytes(response.as_bytes()).await;
+  }
+
+  async fn reply_error(&mut self, response: ErrorResponse) {
+    self.reply_bytes(&response.to_bytes()).await;
+  }
+
+  async fn reply_bytes(&mut self, response: &[u8]) {
+    match timeout(self.config.timeouts.idle, self.stream.write_all(response)).await {
       Ok(r) => {
         if let Err(e) = r {
-          return warn!("stream write error. Err = {:?}", e);
+          warn!("stream write error. Err = {:?}", e)
         }
       }
-      Err(_) => return warn!("stream write timeout"),
+      Err(_) => warn!("stream write timeout"),
     }
   }
 
-  async fn write(&mut self, outbound: &mut TcpStream, outbound_host: &(String, u16), request: &BytesMut) {
-    match timeout(HttpHandler::MAX_TIMEOUT, outbound.write_all(&request)).await {
-      Ok(result) => {
-        if let Err(e) = result {
-          warn!("failed to send http request to target server. Err = {:?}", e);
-          return self.reply(HttpResponse::BadGateway).await;
-        }
+  /// Writes to the tunnel target, the tunnel is already established so failures are only logged.
+  async fn write(&mut self, outbound: &mut Outbound, outbound_host: &(String, u16), data: &[u8]) -> bool {
+    match timeout(self.config.timeouts.idle, outbound.write_all(data)).await {
+      Ok(Ok(())) => true,
+      Ok(Err(e)) => {
+        warn!("failed to send early data to target server. Err = {:?}", e);
+        false
       }
       Err(_) => {
         warn!("timeout while writing to outbound ({:?})", outbound_host);
-        return self.reply(HttpResponse::GatewayTimeout).await;
+        false
       }
     }
   }
@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+
+use bytes::BytesMut;
+use httparse::Header;
+
+use crate::utils::{
+  config::{ForwardedFor, HttpForwardingConfig},
+  header_rule::HeaderRule,
+};
+
+/// Headers only meaningful for a single connection, never forwarded (RFC 9110 section 7.6.1). Transfer-Encoding
+/// is hop-by-hop as well but bodies are relayed with their original framing, so it is kept.
+const HOP_BY_HOP: [&str; 8] = [
+  "connection",
+  "proxy-connection",
+  "keep-alive",
+  "te",
+  "trailer",
+  "upgrade",
+  "proxy-authorization",
+  "proxy-authenticate",
+];
+
+const VIA_PSEUDONYM: &str = "lampo";
+
+/// Ordered header fields of a message, names keep their original case.
+#[derive(Clone, Default)]
+pub struct HttpHeaders {
+  entries: Vec<(String, Vec<u8>)>,
+}
+
+impl HttpHeaders {
+  /// Copies the end-to-end headers, dropping the hop-by-hop ones and those listed in `Connection`.
+  /// `Upgrade` is kept if the message asks for a protocol switch, as the proxy relays upgraded connections.
+  pub fn end_to_end(headers: &[Header]) -> Self {
+    let options = connection_options(headers);
+    let upgrade = options.iter().any(|option| option == "upgrade");
+
+    let entries = headers
+      .iter()
+      .filter(|header| {
+        let name = header.name.to_ascii_lowercase();
+        (upgrade && name == "upgrade") || !(HOP_BY_HOP.contains(&name.as_str()) || options.contains(&name))
+      })
+      .map(|header| (header.name.to_string(), header.value.to_vec()))
+      .collect();
+    Self { entries }
+  }
+
+  pub fn get(&self, name: &str) -> Option<&[u8]> {
+    self
+      .entries
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_slice())
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.get(name).is_some()
+  }
+
+  pub fn remove(&mut self, name: &str) {
+    self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+  }
+
+  pub fn insert(&mut self, name: &str, value: &[u8]) {
+    self.entries.push((name.to_string(), value.to_vec()));
+  }
+
+  /// Appends a value to a comma separated list header, combining every existing field line into one.
+  pub fn append(&mut self, name: &str, value: &str) {
+    let mut combined: Vec<u8> = Vec::new();
+    for (_, existing) in self.entries.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name)) {
+      combined.extend_from_slice(existing);
+      combined.extend_from_slice(b", ");
+    }
+    combined.extend_from_slice(value.as_bytes());
+    self.remove(name);
+    self.insert(name, &combined);
+  }
+
+  pub fn write_to(&self, buf: &mut BytesMut) {
+    for (name, value) in &self.entries {
+      buf.extend_from_slice(name.as_bytes());
+      buf.extend_from_slice(b": ");
+      buf.extend_from_slice(value);
+      buf.extend_from_slice(b"\r\n");
+    }
+  }
+
+  /// Applies the rewrite rules in order, they are validated when loaded.
+  pub fn apply_rules(&mut self, rules: &[HeaderRule]) {
+    for rule in rules {
+      match rule {
+        HeaderRule::Set { name, value } => {
+          self.remove(name);
+          self.insert(name, value.as_bytes());
+        }
+        HeaderRule::Remove { name } => self.remove(name),
+        HeaderRule::Append { name, value } => self.append(name, value),
+      }
+    }
+  }
+
+  /// Adds or suppresses the headers revealing the proxy and its client, according to the product settings.
+  pub fn apply_forwarding(&mut self, config: &HttpForwardingConfig, client_ip: IpAddr, version: &str) {
+    match config.forwarded_for {
+      ForwardedFor::Keep => (),
+      ForwardedFor::Strip => {
+        self.remove("x-forwarded-for");
+        self.remove("forwarded");
+      }
+      ForwardedFor::Add => {
+        let client_ip = client_ip.to_canonical();
+        self.append("X-Forwarded-For", &client_ip.to_string());
+        // IPv6 node names must be quoted, see RFC 7239 section 6.
+        let node = match client_ip {
+          IpAddr::V4(ip) => ip.to_string(),
+          IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        self.append("Forwarded", &format!("for={}", node));
+      }
+    }
+
+    if config.via {
+      self.append("Via", &format!("{} {}", version, VIA_PSEUDONYM));
+    }
+  }
+}
+
+/// Lowercase options of the `Connection` and legacy `Proxy-Connection` headers.
+pub fn connection_options(headers: &[Header]) -> Vec<String> {
+  headers
+    .iter()
+    .filter(|header| header.name.eq_ignore_ascii_case("connection") || header.name.eq_ignore_ascii_case("proxy-connection"))
+    .flat_map(|header| header.value.split(|&c| c == b','))
+    .filter_map(|option| std::str::from_utf8(option).ok())
+    .map(|option| option.trim().to_ascii_lowercase())
+    .filter(|option| !option.is_empty())
+    .collect()
+}
@@ -0,0 +1,361 @@
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+
+use base64::{engine::general_purpose, Engine};
+use bytes::{Bytes, BytesMut};
+use h2::{
+  ext::Protocol,
+  server::{Builder, SendResponse},
+  RecvStream,
+};
+use http::{request::Parts, Method, Request, Response, StatusCode};
+use rand::RngCore;
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::TcpStream,
+  time::{sleep_until, timeout, Instant},
+};
+
+use crate::{
+  cache::session::Session,
+  database::auth_manager::AuthManager,
+  dns::DnsResolver,
+  utils::{
+    auth::parse_authorization,
+    config::ProxyConfig,
+    idle::{Activity, ActivityStream},
+    metered::MeteredStream,
+  },
+};
+
+use self::stream::H2Stream;
+
+use super::{
+  auth::{AuthFailure, HttpAuthenticator},
+  headers::HttpHeaders,
+  outbound::connect_target,
+  parser::HttpResponseHead,
+  utils::error::{ErrorResponse, HttpHandlerError, HttpParserError},
+};
+
+mod stream;
+
+/// Client connection preface, sent first by HTTP/2 clients whether negotiated with ALPN or with prior knowledge.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Reads the first bytes of a client connection, returns whether they are the HTTP/2 preface and the bytes read.
+pub async fn read_preface<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(bool, BytesMut)> {
+  let mut buffer = BytesMut::with_capacity(PREFACE.len());
+  while buffer.len() < PREFACE.len() && PREFACE.starts_with(&buffer) {
+    if stream.read_buf(&mut buffer).await? == 0 {
+      break;
+    }
+  }
+  Ok((buffer.starts_with(PREFACE), buffer))
+}
+
+/// Serves an HTTP/2 client connection, every CONNECT stream is authenticated, accounted and relayed on its own.
+/// Plain requests are answered 501 rather than proxied, which is why `h2` is only offered with ALPN on request.
+pub struct H2Handler {
+  listen_addr: SocketAddr,
+  auth_manager: AuthManager,
+  dns_resolver: DnsResolver,
+  config: Arc<ProxyConfig>,
+  authenticator: HttpAuthenticator,
+  client_addr: SocketAddr,
+  // End of the connection max lifetime.
+  deadline: Instant,
+}
+
+impl H2Handler {
+  pub fn new(client_addr: SocketAddr, listen_addr: SocketAddr, auth_manager: AuthManager, dns_resolver: DnsResolver, config: Arc<ProxyConfig>) -> Self {
+    let deadline = Instant::now() + config.timeouts.max_lifetime;
+    let authenticator = HttpAuthenticator::new(auth_manager.clone(), config.clone(), listen_addr, client_addr);
+    Self {
+      listen_addr,
+      auth_manager,
+      dns_resolver,
+      config,
+      authenticator,
+      client_addr,
+      deadline,
+    }
+  }
+
+  pub async fn execute<S: AsyncRead + AsyncWrite + Unpin>(self, stream: S) {
+    // Extended CONNECT (RFC 8441) lets clients open WebSockets through the connection.
+    let mut builder = Builder::new();
+    builder.enable_connect_protocol().max_concurrent_streams(self.config.http.http2_max_streams);
+    let mut connection = match timeout(self.config.timeouts.handshake, builder.handshake::<_, Bytes>(stream)).await {
+      Ok(Ok(connection)) => connection,
+      Ok(Err(e)) => return debug!("http2 handshake failed ({}). Err = {}", self.client_addr, e),
+      Err(_) => return debug!("http2 handshake timeout ({})", self.client_addr),
+    };
+
+    // Streams only progress while the connection is polled, it is dropped along with them once closed.
+    let handler = Arc::new(self);
+    let streams = OpenStreams::default();
+    let idle = handler.config.timeouts.idle;
+    loop {
+      let accepted = tokio::select! {
+        accepted = connection.accept() => accepted,
+        _ = sleep_until((streams.activity.last() + idle).min(handler.deadline)) => {
+          if Instant::now() >= handler.deadline {
+            return debug!("http2 connection reached its max lifetime ({})", handler.client_addr);
+          }
+          // Open streams have their own idle timeout, the connection only idles without any.
+          if streams.count() > 0 {
+            streams.activity.touch();
+          } else if streams.activity.last() + idle <= Instant::now() {
+            return debug!("http2 connection idle for {:?} ({})", idle, handler.client_addr);
+          }
+          continue;
+        }
+      };
+      match accepted {
+        Some(Ok((request, respond))) => {
+          let (handler, stream) = (handler.clone(), streams.open());
+          tokio::spawn(async move {
+            handler.handle(request, respond).await;
+            drop(stream);
+          });
+        }
+        Some(Err(e)) => return debug!("http2 connection error ({}). Err = {}", handler.client_addr, e),
+        None => return,
+      }
+    }
+  }
+
+  async fn handle(&self, request: Request<RecvStream>, mut respond: SendResponse<Bytes>) {
+    let (parts, body) = request.into_parts();
+    if parts.method != Method::CONNECT {
+      return reply_error(&mut respond, ErrorResponse::UnsupportedMethod);
+    }
+    let protocol = parts.extensions.get::<Protocol>().map(|protocol| protocol.as_str().to_string());
+    let Some(authority) = parts.uri.authority().cloned() else {
+      return reply_error(&mut respond, ErrorResponse::InvalidRequest);
+    };
+
+    // The target is the authority, extended CONNECT may omit the port of its scheme.
+    let port = match (authority.port_u16(), &protocol) {
+      (Some(port), _) => port,
+      (None, Some(_)) if parts.uri.scheme_str() == Some("http") => 80,
+      _ => return reply_error(&mut respond, ErrorResponse::InvalidRequest),
+    };
+    let host = (authority.host().trim_start_matches('[').trim_end_matches(']').to_string(), port);
+    // WebSockets are opened with an HTTP/1.1 upgrade, which requires a plaintext target.
+    if protocol
+      .as_deref()
+      .is_some_and(|protocol| protocol != "websocket" || parts.uri.scheme_str() != Some("http"))
+    {
+      return reply_error(&mut respond, ErrorResponse::UnsupportedProtocol);
+    }
+
+    let authentication = parts
+      .headers
+      .get(http::header::PROXY_AUTHORIZATION)
+      .and_then(|value| value.to_str().ok())
+      .and_then(parse_authorization);
+    let cache_value = match self.authenticator.authenticate(authentication.as_ref(), "CONNECT", &[authority.as_str()]).await {
+      Ok(cv) => cv,
+      Err(AuthFailure::Expired) => return reply_error(&mut respond, ErrorResponse::OrderExpired),
+      Err(AuthFailure::Denied { product, stale }) => return self.reply_authentication_required(&mut respond, product.as_deref(), stale).await,
+    };
+
+    if !self.auth_manager.try_consume_request(&cache_value) {
+      debug!("requests limit reached for order {}", cache_value.order_id);
+      return reply_error(&mut respond, ErrorResponse::RequestsLimitReached);
+    }
+
+    debug!("CONNECT {} {:?} over http2 (Order: {})", authority, protocol, cache_value.order_id);
+
+    let session = self.auth_manager.open_session(&cache_value, self.listen_addr.ip());
+    let outbound = match connect_target(&self.dns_resolver, self.listen_addr, &host, self.config.timeouts.connect).await {
+      Ok(outbound) => outbound,
+      Err(response) => return reply_error(&mut respond, response),
+    };
+    let activity = Activity::new();
+    let mut outbound = ActivityStream::new(MeteredStream::new(outbound, session.bandwidth.clone()), activity.clone());
+
+    let mut early_data = Bytes::new();
+    let response = match protocol {
+      None => Response::new(()),
+      Some(_) => match self.open_websocket(&parts, &cache_value.product, &mut outbound).await {
+        Ok((response, data)) => {
+          early_data = data;
+          response
+        }
+        Err(e) => {
+          warn!("{} ({:?})", e, &host);
+          return reply_error(&mut respond, ErrorResponse::from_exchange(&HttpHandlerError::Response(e)));
+        }
+      },
+    };
+
+    let success = response.status().is_success();
+    let send = match respond.send_response(response, !success) {
+      Ok(send) => send,
+      Err(e) => return debug!("failed to answer http2 stream ({:?}). Err = {}", &host, e),
+    };
+    if !success {
+      return;
+    }
+
+    let mut tunnel = H2Stream::new(send, body);
+    if !early_data.is_empty() && tunnel.write_all(&early_data).await.is_err() {
+      return;
+    }
+    self.relay(&mut tunnel, &mut outbound, activity, &host, &session).await;
+  }
+
+  /// Opens the WebSocket on the target with an HTTP/1.1 upgrade request. The 101 answer is translated to the 200
+  /// answering an extended CONNECT, the data following it belongs to the WebSocket.
+  async fn open_websocket(
+    &self,
+    parts: &Parts,
+    product: &str,
+    outbound: &mut ActivityStream<MeteredStream<TcpStream>>,
+  ) -> Result<(Response<()>, Bytes), HttpParserError> {
+    let headers: Vec<httparse::Header> = parts
+      .headers
+      .iter()
+      .map(|(name, value)| httparse::Header {
+        name: name.as_str(),
+        value: value.as_bytes(),
+      })
+      .collect();
+    let mut headers = HttpHeaders::end_to_end(&headers);
+    // The handshake key is specific to HTTP/1.1, the target answer is not checked against it.
+    headers.remove("sec-websocket-key");
+    headers.remove("sec-websocket-version");
+    headers.remove("host");
+    let forwarding = self.config.http.forwarding(product);
+    headers.apply_forwarding(forwarding, self.client_addr.ip(), "2");
+
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut request = BytesMut::new();
+    request.extend_from_slice(
+      format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        path,
+        parts.uri.authority().map_or("", |authority| authority.as_str()),
+        general_purpose::STANDARD.encode(key)
+      )
+      .as_bytes(),
+    );
+    headers.write_to(&mut request);
+    request.extend_from_slice(b"\r\n");
+    outbound.write_all(&request).await.map_err(HttpParserError::StreamReadError)?;
+
+    let mut buffer = BytesMut::new();
+    let head = HttpResponseHead::read(outbound, &mut buffer, "GET").await?;
+    if head.status != 101 {
+      let status = StatusCode::from_u16(head.status).unwrap_or(StatusCode::BAD_GATEWAY);
+      return Ok((Response::builder().status(status).body(()).unwrap(), Bytes::new()));
+    }
+
+    let mut response = Response::builder().status(StatusCode::OK);
+    for name in ["sec-websocket-protocol", "sec-websocket-extensions"] {
+      if let Some(value) = head.headers.get(name) {
+        response = response.header(name, value);
+      }
+    }
+    let response = response.body(()).map_err(|_| HttpParserError::Unknown)?;
+    Ok((response, buffer.freeze()))
+  }
+
+  /// Relays the stream data both ways until either side closes, with the same timeouts as HTTP/1.1 tunnels.
+  async fn relay(
+    &self,
+    tunnel: &mut H2Stream,
+    outbound: &mut ActivityStream<MeteredStream<TcpStream>>,
+    activity: Activity,
+    host: &(String, u16),
+    session: &Session,
+  ) {
+    tokio::select! {
+      result = activity.watch(tokio::io::copy_bidirectional(tunnel, outbound), self.config.timeouts.idle, self.deadline) => {
+        if let Err(e) = result {
+          debug!("{} on http2 tunnel ({:?})", e, host);
+        }
+      }
+      _ = session.terminated() => debug!("session terminated ({:?})", host),
+    }
+  }
+
+  async fn reply_authentication_required(&self, respond: &mut SendResponse<Bytes>, product: Option<&str>, stale: bool) {
+    let mut response = Response::builder()
+      .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+      .header("x-lampo-error", "auth-required");
+    for challenge in self.authenticator.challenges(product, stale).await {
+      response = response.header(http::header::PROXY_AUTHENTICATE, challenge);
+    }
+    send_final(respond, response.body(()).unwrap(), "Access Denied");
+  }
+}
+
+/// Streams being served on a connection, the activity is touched whenever one opens or ends.
+#[derive(Clone)]
+struct OpenStreams {
+  count: Arc<AtomicUsize>,
+  activity: Activity,
+}
+
+impl Default for OpenStreams {
+  fn default() -> Self {
+    Self {
+      count: Arc::new(AtomicUsize::new(0)),
+      activity: Activity::new(),
+    }
+  }
+}
+
+impl OpenStreams {
+  fn count(&self) -> usize {
+    self.count.load(Ordering::Relaxed)
+  }
+
+  fn open(&self) -> OpenStream {
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.activity.touch();
+    OpenStream(self.clone())
+  }
+}
+
+struct OpenStream(OpenStreams);
+
+impl Drop for OpenStream {
+  fn drop(&mut self) {
+    self.0.count.fetch_sub(1, Ordering::Relaxed);
+    self.0.activity.touch();
+  }
+}
+
+fn reply_error(respond: &mut SendResponse<Bytes>, error: ErrorResponse) {
+  let response = Response::builder()
+    .status(error.status().0)
+    .header("x-lampo-error", error.code())
+    .header(http::header::CONTENT_TYPE, "text/plain")
+    .body(())
+    .unwrap();
+  send_final(respond, response, error.message());
+}
+
+/// Answers the stream with a short body, ending it.
+fn send_final(respond: &mut SendResponse<Bytes>, response: Response<()>, body: &'static str) {
+  match respond.send_response(response, false) {
+    Ok(mut send) => {
+      if let Err(e) = send.send_data(Bytes::from_static(body.as_bytes()), true) {
+        debug!("failed to answer http2 stream. Err = {}", e);
+      }
+    }
+    Err(e) => debug!("failed to answer http2 stream. Err = {}", e),
+  }
+}
@@ -0,0 +1,85 @@
+use std::{
+  io,
+  pin::Pin,
+  task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Byte stream over the DATA frames of an HTTP/2 stream, once the CONNECT request is answered.
+pub struct H2Stream {
+  send: SendStream<Bytes>,
+  recv: RecvStream,
+  // Received data not read yet.
+  pending: Bytes,
+}
+
+impl H2Stream {
+  pub fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+    Self {
+      send,
+      recv,
+      pending: Bytes::new(),
+    }
+  }
+}
+
+fn into_io(e: h2::Error) -> io::Error {
+  if e.is_io() {
+    e.into_io().unwrap()
+  } else {
+    io::Error::new(io::ErrorKind::ConnectionReset, e)
+  }
+}
+
+impl AsyncRead for H2Stream {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    while self.pending.is_empty() {
+      match ready!(self.recv.poll_data(cx)) {
+        Some(Ok(data)) => {
+          // Reopen the flow control window as soon as the data is taken off the stream.
+          let _ = self.recv.flow_control().release_capacity(data.len());
+          self.pending = data;
+        }
+        Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+        None => return Poll::Ready(Ok(())),
+      }
+    }
+    let len = self.pending.len().min(buf.remaining());
+    buf.put_slice(&self.pending[..len]);
+    self.pending.advance(len);
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl AsyncWrite for H2Stream {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    if buf.is_empty() {
+      return Poll::Ready(Ok(0));
+    }
+    self.send.reserve_capacity(buf.len());
+    loop {
+      match ready!(self.send.poll_capacity(cx)) {
+        Some(Ok(0)) => continue,
+        Some(Ok(capacity)) => {
+          let len = capacity.min(buf.len());
+          self.send.send_data(Bytes::copy_from_slice(&buf[..len]), false).map_err(into_io)?;
+          return Poll::Ready(Ok(len));
+        }
+        Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+        None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+      }
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    // Ends the stream, the client sees the half-close like a TCP FIN.
+    Poll::Ready(self.send.send_data(Bytes::new(), true).map_err(into_io))
+  }
+}
@@ -1,31 +1,59 @@
 use std::mem::drop;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{Barrier, Semaphore};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+  sync::{Barrier, Semaphore},
+  time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
 
-use self::handler::HttpHandler;
-use crate::{database::auth_manager::AuthManager, dns::DnsResolver, utils::socket::make_listener};
+use self::{
+  handler::HttpHandler,
+  http2::{read_preface, H2Handler},
+};
+use crate::{
+  database::auth_manager::AuthManager,
+  dns::DnsResolver,
+  utils::{config::ProxyConfig, rewind::Rewind, socket::make_listener},
+};
 
+mod auth;
+mod body;
 mod handler;
+mod headers;
+mod http2;
+mod outbound;
 mod parser;
 mod utils;
 
 #[derive(Clone)]
 pub struct HttpProxy {
   listen_addr: SocketAddr,
-  backlog: u32,
   auth_manager: AuthManager,
   dns_resolver: DnsResolver,
+  config: Arc<ProxyConfig>,
+  // Set for the HTTPS listener, connections are decrypted before being handled.
+  tls: Option<TlsAcceptor>,
   barrier: Arc<Barrier>,
   semaphore: Arc<Semaphore>,
 }
 
 impl HttpProxy {
-  pub fn new(addr: SocketAddr, backlog: u32, auth_manager: AuthManager, dns_resolver: DnsResolver, barrier: Arc<Barrier>, semaphore: Arc<Semaphore>) -> Self {
+  pub fn new(
+    addr: SocketAddr,
+    auth_manager: AuthManager,
+    dns_resolver: DnsResolver,
+    config: Arc<ProxyConfig>,
+    tls: Option<TlsAcceptor>,
+    barrier: Arc<Barrier>,
+    semaphore: Arc<Semaphore>,
+  ) -> Self {
     Self {
       listen_addr: addr,
-      backlog,
       auth_manager,
       dns_resolver,
+      config,
+      tls,
       barrier,
       semaphore,
     }
@@ -34,7 +62,7 @@ impl HttpProxy {
   pub async fn listen(&self) {
     let _permit = self.semaphore.acquire().await.expect("failed to acquire semaphore permit on preload");
 
-    let listener = match make_listener(self.listen_addr, self.backlog).await {
+    let listener = match make_listener(self.listen_addr, self.config.backlog).await {
       Ok(l) => l,
       Err(e) => {
         return error!("Failed to initialize HttpProxy listener. Err = {:?}", e);
@@ -47,14 +75,53 @@ impl HttpProxy {
 
     debug!("HttpProxy {} passed barrier, starting listener", self.listen_addr);
 
-    while let Ok((mut stream, _)) = listener.accept().await {
+    while let Ok((stream, client_addr)) = listener.accept().await {
       let listen_addr = self.listen_addr;
       let auth_manager = self.auth_manager.clone();
       let dns_resolver = self.dns_resolver.clone();
+      let config = self.config.clone();
+      let tls = self.tls.clone();
 
       tokio::spawn(async move {
-        HttpHandler::new(&mut stream, listen_addr, auth_manager, dns_resolver).execute().await;
+        let Some(acceptor) = tls else {
+          return serve(stream, client_addr, listen_addr, auth_manager, dns_resolver, config).await;
+        };
+
+        match timeout(config.timeouts.handshake, acceptor.accept(stream)).await {
+          Ok(Ok(stream)) => serve(stream, client_addr, listen_addr, auth_manager, dns_resolver, config).await,
+          Ok(Err(e)) => debug!("TLS handshake failed ({}). Err = {}", client_addr, e),
+          Err(_) => debug!("TLS handshake timeout ({})", client_addr),
+        }
       });
     }
   }
 }
+
+/// Serves a client connection with HTTP/2 if it starts with the HTTP/2 preface, be it negotiated with ALPN or
+/// prior knowledge, and with HTTP/1.x otherwise.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+  mut stream: S,
+  client_addr: SocketAddr,
+  listen_addr: SocketAddr,
+  auth_manager: AuthManager,
+  dns_resolver: DnsResolver,
+  config: Arc<ProxyConfig>,
+) {
+  let (is_http2, prefix) = match timeout(config.timeouts.handshake, read_preface(&mut stream)).await {
+    Ok(Ok(read)) => read,
+    Ok(Err(e)) => return debug!("failed to read from client ({}). Err = {}", client_addr, e),
+    Err(_) => return debug!("timeout while reading from client ({})", client_addr),
+  };
+  let mut stream = Rewind::new(prefix.freeze(), stream);
+
+  if is_http2 {
+    return H2Handler::new(client_addr, listen_addr, auth_manager, dns_resolver, config)
+      .execute(stream)
+      .await;
+  }
+  HttpHandler::new(&mut stream, client_addr, listen_addr, auth_manager, dns_resolver, config)
+    .execute()
+    .await;
+  // Sends close_notify over TLS, so the client can tell a complete response from a truncated one.
+  let _ = stream.shutdown().await;
+}
@@ -0,0 +1,37 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{dns::DnsResolver, utils::socket::make_outbound};
+
+use super::utils::error::ErrorResponse;
+
+/// Resolves and connects to the target host from the listen address, failures are mapped to the response to answer.
+pub async fn connect_target(
+  dns_resolver: &DnsResolver,
+  listen_addr: SocketAddr,
+  host: &(String, u16),
+  connect_timeout: Duration,
+) -> Result<TcpStream, ErrorResponse> {
+  let target_addr = match dns_resolver.resolve(&host.0, host.1).await {
+    Ok(h) => h,
+    Err(e) => {
+      warn!("failed to resolve host {:?}. Err = {}", host, e);
+      return Err(ErrorResponse::from_resolve(e.as_ref()));
+    }
+  };
+
+  let bind_addr = SocketAddr::from((listen_addr.ip(), 0));
+
+  match timeout(connect_timeout, make_outbound(bind_addr, target_addr)).await {
+    Ok(Ok(outbound)) => Ok(outbound),
+    Ok(Err(e)) => {
+      warn!("failed to create outbound TcpStream ({:?}). Err = {}", host, e);
+      Err(ErrorResponse::from_io(&e))
+    }
+    Err(_) => {
+      warn!("timeout while connecting to outbound ({:?})", host);
+      Err(ErrorResponse::TargetTimeout)
+    }
+  }
+}
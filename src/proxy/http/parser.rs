@@ -1,17 +1,23 @@
 use std::time::Duration;
 
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use httparse::Header;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use url::Url;
 
-use crate::utils;
+use crate::utils::auth::{parse_authorization, ProxyAuthorization};
 
-use super::utils::error::HttpParserError;
+use super::{
+  body::BodyFraming,
+  headers::{connection_options, HttpHeaders},
+  utils::error::HttpParserError,
+};
 
-pub struct HttpParser<'a> {
-  stream: &'a mut TcpStream,
+/// Reads requests from a client connection, bytes received past a request head are kept for its body
+/// and the following requests.
+pub struct HttpParser {
   buffer: BytesMut,
+  max_header_size: usize,
 }
 
 pub struct HttpRequestData {
@@ -19,52 +25,71 @@ pub struct HttpRequestData {
   pub method: String,
   pub version: String,
   pub host: (String, u16),
-  pub headers: BytesMut,
-  pub body: BytesMut,
-  pub authentication: Option<(String, String)>,
+  /// End-to-end headers, hop-by-hop ones are stripped.
+  pub headers: HttpHeaders,
+  pub authentication: Option<ProxyAuthorization>,
+  pub body_framing: BodyFraming,
+  /// Whether the client allows the connection to be reused after this request.
+  pub keep_alive: bool,
+}
+
+/// Response head read from the target server.
+pub struct HttpResponseHead {
+  pub status: u16,
+  status_line: BytesMut,
+  /// End-to-end headers, hop-by-hop ones are stripped.
+  pub headers: HttpHeaders,
+  pub body_framing: BodyFraming,
+  pub keep_alive: bool,
 }
 
 impl HttpRequestData {
-  pub fn new(
-    path: &str,
-    method: &str,
-    version: &str,
-    headers: BytesMut,
-    body: BytesMut,
-    host: (String, u16),
-    authentication: Option<(String, String)>,
-  ) -> Self {
-    Self {
-      path: path.to_string(),
-      method: method.to_string(),
-      version: version.to_string(),
-      host,
-      headers,
-      body,
-      authentication,
+  /// Whether the client asks to switch protocols, e.g. to WebSocket. `Upgrade` is only kept by the header
+  /// filtering when listed in `Connection`, and HTTP/1.0 has no protocol switch.
+  pub fn is_upgrade(&self) -> bool {
+    self.version != "1.0" && self.headers.contains("upgrade")
+  }
+
+  /// Removes `Expect: 100-continue`, returns whether the client waits for the interim response before sending its body.
+  /// HTTP/1.0 clients do not know it, see RFC 9110 section 10.1.1.
+  pub fn take_expect_continue(&mut self) -> bool {
+    let expect = self
+      .headers
+      .get("expect")
+      .is_some_and(|value| value.trim_ascii().eq_ignore_ascii_case(b"100-continue"));
+    if !expect {
+      return false;
     }
+    self.headers.remove("expect");
+    self.version != "1.0" && self.body_framing != BodyFraming::Empty
+  }
+
+  /// Whether sending the request twice has the same effect as once, see RFC 9110 section 9.2.2.
+  pub fn is_idempotent(&self) -> bool {
+    matches!(self.method.as_str(), "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
   }
 
-  pub fn into_request(&mut self) -> BytesMut {
+  pub fn build_request(&mut self) -> BytesMut {
     let mut request = BytesMut::new();
     let path = self.convert_absolute_uri_to_path().unwrap_or(self.path.clone());
 
     // Start line
     request.extend_from_slice(format!("{} {} HTTP/{}\r\n", self.method, path, self.version).as_bytes());
 
-    // Headers
-    request.extend_from_slice(&self.headers);
-
-    if self.body.is_empty() {
-      self.body.extend_from_slice(b"\r\n");
+    // Headers, the body is relayed separately according to its framing.
+    if !self.is_upgrade() {
+      self.headers.remove("upgrade");
+    }
+    self.headers.write_to(&mut request);
+    if self.is_upgrade() {
+      request.extend_from_slice(b"Connection: upgrade\r\n");
     }
-    // Rest of the request, trailing headers, extensions or body all in one chunk.
-    request.extend_from_slice(&self.body);
+    request.extend_from_slice(b"\r\n");
 
     request
   }
 
-  fn convert_absolute_uri_to_path(&self) -> Option<String> {
+  pub fn convert_absolute_uri_to_path(&self) -> Option<String> {
     if self.path.starts_with("http://") || self.path.starts_with("https://") {
       if let Ok(url) = Url::parse(&self.path) {
         let mut path = url.path().to_string();
@@ -79,83 +104,97 @@ impl HttpRequestData {
       }
     }
 
-    return None;
+    None
   }
 }
 
-impl<'a> HttpParser<'a> {
+impl HttpParser {
   const MAX_BUF: usize = 16384;
-  const MAX_TIMEOUT: Duration = Duration::from_secs(10);
 
-  pub fn new(stream: &mut TcpStream) -> HttpParser {
+  pub fn new(max_header_size: usize) -> HttpParser {
     HttpParser {
-      stream,
-      buffer: BytesMut::with_capacity(HttpParser::MAX_BUF / 2),
+      buffer: BytesMut::with_capacity(max_header_size.min(HttpParser::MAX_BUF) / 2),
+      max_header_size,
+    }
+  }
+
+  /// Bytes received past the last request head.
+  pub fn buffer(&mut self) -> &mut BytesMut {
+    &mut self.buffer
+  }
+
+  /// Reads until a whole request head is buffered, it may arrive in any number of segments.
+  pub async fn read<S: AsyncRead + Unpin>(&mut self, stream: &mut S, timeout: Duration) -> Result<HttpRequestData, HttpParserError> {
+    match tokio::time::timeout(timeout, self.read_head(stream)).await {
+      Ok(result) => result,
+      Err(_) => Err(HttpParserError::StreamReadTimeout),
     }
   }
 
-  pub async fn read(&mut self) -> Result<HttpRequestData, HttpParserError> {
+  async fn read_head<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<HttpRequestData, HttpParserError> {
     loop {
-      match tokio::time::timeout(HttpParser::MAX_TIMEOUT, self.stream.read_buf(&mut self.buffer)).await {
-        Ok(result) => {
-          match result {
-            Ok(bytes_read) => {
-              if bytes_read == 0 {
-                // Client closed the connection.
-                break;
-              }
-              if self.buffer.len() > HttpParser::MAX_BUF {
-                return Err(HttpParserError::BufferLimitExceeded);
-              }
-
-              let request = self.parse_request().await;
-
-              self.buffer.clear();
-
-              return request;
-            }
-            Err(e) => return Err(HttpParserError::StreamReadError(e)),
-          }
+      // A pipelined request may already be buffered.
+      if !self.buffer.is_empty() {
+        if let Some((request, len)) = self.parse_request()? {
+          self.buffer.advance(len);
+          return Ok(request);
         }
-        Err(_) => return Err(HttpParserError::StreamReadTimeout),
+      }
+      if self.buffer.len() >= self.max_header_size {
+        return Err(HttpParserError::HeaderTooLarge(self.max_header_size));
+      }
+
+      // Never buffer more than the head limit, the body is streamed separately.
+      let limit = (self.max_header_size - self.buffer.len()) as u64;
+      match stream.take(limit).read_buf(&mut self.buffer).await {
+        Ok(0) => return Err(HttpParserError::ClosedConnection),
+        Ok(_) => (),
+        Err(e) => return Err(HttpParserError::StreamReadError(e)),
       }
     }
-    Err(HttpParserError::ClosedConnection)
   }
 
-  async fn parse_request(&mut self) -> Result<HttpRequestData, HttpParserError> {
+  fn parse_request(&self) -> Result<Option<(HttpRequestData, usize)>, HttpParserError> {
     let mut headers = [httparse::EMPTY_HEADER; 64];
     let mut req = httparse::Request::new(&mut headers);
 
     match req.parse(&self.buffer) {
+      Ok(httparse::Status::Complete(len)) if len > self.max_header_size => Err(HttpParserError::HeaderTooLarge(self.max_header_size)),
       Ok(httparse::Status::Complete(len)) => {
         let method = req.method.ok_or(HttpParserError::MissingMethod)?;
         let path = req.path.ok_or(HttpParserError::MissingPath)?;
         let version_u8 = req.version.ok_or(HttpParserError::MissingVersion)?;
 
-        let (host_header, proxy_authorization_header, headers) = self.parse_headers(&req.headers).await?;
+        let (host_header, proxy_authorization_header, mut headers) = self.parse_headers(req.headers);
         let host = self.parse_host(method, path, host_header).ok_or(HttpParserError::MissingHost)?;
 
-        let body = BytesMut::from(&self.buffer[len..]);
-        let version = self.parse_version(version_u8)?;
+        let body_framing = match BodyFraming::from_headers(req.headers)? {
+          // Requests without framing have no body, only a chunked transfer coding delimits it otherwise.
+          None => BodyFraming::Empty,
+          Some(BodyFraming::UntilClose) => return Err(HttpParserError::UnsupportedTransferEncoding),
+          Some(framing) => framing,
+        };
+        // A chunked message must not be forwarded with a Content-Length as well, see RFC 9112 section 6.3.
+        if body_framing == BodyFraming::Chunked {
+          headers.remove("content-length");
+        }
 
-        return Ok(HttpRequestData::new(
-          path,
-          method,
-          version,
-          headers,
-          body,
+        let request = HttpRequestData {
+          path: path.to_string(),
+          method: method.to_string(),
+          version: self.parse_version(version_u8)?.to_string(),
           host,
-          self.parse_auth_header(proxy_authorization_header).await,
-        ));
-      }
-      Ok(httparse::Status::Partial) => (),
-      Err(_) => {
-        return Err(HttpParserError::Unknown);
+          headers,
+          authentication: self.parse_auth_header(proxy_authorization_header),
+          body_framing,
+          keep_alive: is_keep_alive(version_u8, req.headers),
+        };
+        Ok(Some((request, len)))
       }
+      Ok(httparse::Status::Partial) => Ok(None),
+      Err(httparse::Error::TooManyHeaders) => Err(HttpParserError::HeaderTooLarge(self.max_header_size)),
+      Err(_) => Err(HttpParserError::Unknown),
     }
-
-    return Err(HttpParserError::Unknown);
   }
 
   fn parse_version(&self, version: u8) -> Result<&str, HttpParserError> {
@@ -166,39 +205,31 @@ impl<'a> HttpParser<'a> {
     }
   }
 
-  async fn parse_headers(&self, headers: &[Header<'a>]) -> Result<(Option<Header>, Option<Header>, BytesMut), HttpParserError> {
+  fn parse_headers<'h>(&self, headers: &[Header<'h>]) -> (Option<Header<'h>>, Option<Header<'h>>, HttpHeaders) {
     let mut host: Option<Header> = None;
     let mut proxy_authorization: Option<Header> = None;
 
-    let mut buf = BytesMut::new();
-
     for header in headers {
-      if header.name.to_string().eq_ignore_ascii_case("host") {
+      if header.name.eq_ignore_ascii_case("host") {
         host = Some(header.to_owned());
       }
-      if header.name.to_string().eq_ignore_ascii_case("proxy-authorization") {
+      if header.name.eq_ignore_ascii_case("proxy-authorization") {
         proxy_authorization = Some(header.to_owned());
-        // We do not want to add the proxy-authorization header to the actual outgoing request.
-        continue;
       }
-
-      buf.extend_from_slice(header.name.as_bytes());
-      buf.extend_from_slice(b": ");
-      buf.extend_from_slice(header.value);
-      buf.extend_from_slice(b"\r\n");
     }
 
-    Ok((host, proxy_authorization, buf))
+    // Proxy-Authorization is hop-by-hop, it never reaches the target server.
+    (host, proxy_authorization, HttpHeaders::end_to_end(headers))
   }
 
-  async fn parse_auth_header(&self, auth_header: Option<Header<'a>>) -> Option<(String, String)> {
+  fn parse_auth_header(&self, auth_header: Option<Header>) -> Option<ProxyAuthorization> {
     let auth_header_value = String::from_utf8(auth_header?.value.to_owned()).ok()?;
 
-    match utils::auth::parse_credentials(&auth_header_value) {
+    match parse_authorization(&auth_header_value) {
       Some(a) => Some(a),
       None => {
         debug!("Failed to parse authentication header. Raw = {:?}", auth_header_value);
-        return None;
+        None
       }
     }
   }
@@ -207,7 +238,7 @@ impl<'a> HttpParser<'a> {
     // Special handling for CONNECT requests
     if method == "CONNECT" {
       let parts: Vec<&str> = path.splitn(2, ':').collect();
-      let host = parts.get(0)?;
+      let host = parts.first()?;
       let port = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(443);
       return Some((host.to_string(), port));
     }
@@ -237,3 +268,82 @@ impl<'a> HttpParser<'a> {
     }
   }
 }
+
+impl HttpResponseHead {
+  /// Serializes the head for the client, `connection` being the options of the client connection.
+  pub fn build_response(&self, connection: Option<&str>) -> BytesMut {
+    let mut response = BytesMut::new();
+    response.extend_from_slice(&self.status_line);
+    self.headers.write_to(&mut response);
+    if let Some(connection) = connection {
+      response.extend_from_slice(format!("Connection: {}\r\n", connection).as_bytes());
+    }
+    response.extend_from_slice(b"\r\n");
+    response
+  }
+
+  /// Reads a response head from the target server into `buffer`, which keeps the bytes received past it.
+  pub async fn read<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut BytesMut, request_method: &str) -> Result<Self, HttpParserError> {
+    loop {
+      if !buffer.is_empty() {
+        if let Some(response) = HttpResponseHead::parse(buffer, request_method)? {
+          return Ok(response);
+        }
+      }
+      if buffer.len() > HttpParser::MAX_BUF {
+        return Err(HttpParserError::BufferLimitExceeded);
+      }
+      match stream.read_buf(buffer).await {
+        Ok(0) => return Err(HttpParserError::ClosedConnection),
+        Ok(_) => (),
+        Err(e) => return Err(HttpParserError::StreamReadError(e)),
+      }
+    }
+  }
+
+  fn parse(buffer: &mut BytesMut, request_method: &str) -> Result<Option<Self>, HttpParserError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = httparse::Response::new(&mut headers);
+
+    let len = match res.parse(buffer) {
+      Ok(httparse::Status::Complete(len)) => len,
+      Ok(httparse::Status::Partial) => return Ok(None),
+      Err(_) => return Err(HttpParserError::Unknown),
+    };
+    let status = res.code.ok_or(HttpParserError::Unknown)?;
+    let version = res.version.ok_or(HttpParserError::MissingVersion)?;
+
+    // See RFC 9112 section 6.3, responses to HEAD and 1xx, 204 or 304 responses never have a body.
+    let body_framing = if request_method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304 {
+      BodyFraming::Empty
+    } else {
+      BodyFraming::from_headers(res.headers)?.unwrap_or(BodyFraming::UntilClose)
+    };
+    let keep_alive = body_framing != BodyFraming::UntilClose && is_keep_alive(version, res.headers);
+
+    let mut headers = HttpHeaders::end_to_end(res.headers);
+    if body_framing == BodyFraming::Chunked {
+      headers.remove("content-length");
+    }
+    let mut status_line = buffer.split_to(len);
+    let status_line_len = status_line.iter().position(|&c| c == b'\n').map_or(status_line.len(), |pos| pos + 1);
+    status_line.truncate(status_line_len);
+
+    Ok(Some(HttpResponseHead {
+      status,
+      status_line,
+      headers,
+      body_framing,
+      keep_alive,
+    }))
+  }
+}
+
+/// HTTP/1.1 connections are persistent unless closed explicitly, HTTP/1.0 ones only if asked to be kept alive.
+fn is_keep_alive(version: u8, headers: &[Header]) -> bool {
+  let options = connection_options(headers);
+  if options.iter().any(|option| option == "close") {
+    return false;
+  }
+  version >= 1 || options.iter().any(|option| option == "keep-alive")
+}
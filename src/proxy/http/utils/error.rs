@@ -1,4 +1,7 @@
+use std::io::ErrorKind;
+
 use thiserror::Error;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 
 #[derive(Error, Debug)]
 pub enum HttpParserError {
@@ -12,6 +15,14 @@ pub enum HttpParserError {
   MissingHost,
   #[error("missing http request path")]
   MissingPath,
+  #[error("invalid content-length header")]
+  InvalidContentLength,
+  #[error("unsupported request transfer-encoding")]
+  UnsupportedTransferEncoding,
+  #[error("request head exceeds the {0} bytes limit")]
+  HeaderTooLarge(usize),
+  #[error("protocol switch without upgrade request")]
+  UnexpectedUpgrade,
   #[error("exceeded buffer limit during read")]
   BufferLimitExceeded,
   #[error("error while reading from stream. Err = {0}")]
@@ -23,3 +34,177 @@ pub enum HttpParserError {
   #[error("unknown http parser error")]
   Unknown,
 }
+
+#[derive(Error, Debug)]
+pub enum HttpHandlerError {
+  #[error("error while relaying the request body. Err = {0}")]
+  Request(std::io::Error),
+  #[error("invalid response from the target server. Err = {0}")]
+  Response(#[from] HttpParserError),
+  #[error("error while relaying the http exchange. Err = {0}")]
+  Relay(#[from] std::io::Error),
+}
+
+impl HttpHandlerError {
+  /// Whether the target connection was found closed, as kept-alive connections are once the target drops them.
+  pub fn is_connection_lost(&self) -> bool {
+    match self {
+      HttpHandlerError::Request(e) | HttpHandlerError::Relay(e) => e.kind() != ErrorKind::InvalidData,
+      HttpHandlerError::Response(e) => matches!(e, HttpParserError::ClosedConnection | HttpParserError::StreamReadError(_)),
+    }
+  }
+}
+
+/// Failure answered to the client, identified by the machine-readable `X-Lampo-Error` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorResponse {
+  InvalidRequest,
+  InvalidContentLength,
+  UnsupportedTransferEncoding,
+  InvalidRequestBody,
+  HeaderTooLarge,
+  UnsupportedMethod,
+  UnsupportedProtocol,
+  OrderExpired,
+  RequestsLimitReached,
+  DnsNotFound,
+  DnsFailure,
+  DnsTimeout,
+  TargetRefused,
+  TargetUnreachable,
+  TargetTimeout,
+  TargetClosed,
+  ConnectFailed,
+  InvalidResponse,
+}
+
+impl ErrorResponse {
+  pub fn status(self) -> (u16, &'static str) {
+    match self {
+      ErrorResponse::InvalidRequest | ErrorResponse::InvalidContentLength | ErrorResponse::UnsupportedTransferEncoding | ErrorResponse::InvalidRequestBody => {
+        (400, "Bad Request")
+      }
+      ErrorResponse::HeaderTooLarge => (431, "Request Header Fields Too Large"),
+      ErrorResponse::UnsupportedMethod | ErrorResponse::UnsupportedProtocol => (501, "Not Implemented"),
+      ErrorResponse::OrderExpired => (403, "Forbidden"),
+      ErrorResponse::RequestsLimitReached => (429, "Too Many Requests"),
+      ErrorResponse::DnsTimeout | ErrorResponse::TargetTimeout => (504, "Gateway Timeout"),
+      ErrorResponse::DnsNotFound
+      | ErrorResponse::DnsFailure
+      | ErrorResponse::TargetRefused
+      | ErrorResponse::TargetUnreachable
+      | ErrorResponse::TargetClosed
+      | ErrorResponse::ConnectFailed
+      | ErrorResponse::InvalidResponse => (502, "Bad Gateway"),
+    }
+  }
+
+  pub fn code(self) -> &'static str {
+    match self {
+      ErrorResponse::InvalidRequest => "invalid-request",
+      ErrorResponse::InvalidContentLength => "invalid-content-length",
+      ErrorResponse::UnsupportedTransferEncoding => "unsupported-transfer-encoding",
+      ErrorResponse::InvalidRequestBody => "invalid-request-body",
+      ErrorResponse::HeaderTooLarge => "header-too-large",
+      ErrorResponse::UnsupportedMethod => "unsupported-method",
+      ErrorResponse::UnsupportedProtocol => "unsupported-protocol",
+      ErrorResponse::OrderExpired => "order-expired",
+      ErrorResponse::RequestsLimitReached => "requests-limit-reached",
+      ErrorResponse::DnsNotFound => "dns-not-found",
+      ErrorResponse::DnsFailure => "dns-failure",
+      ErrorResponse::DnsTimeout => "dns-timeout",
+      ErrorResponse::TargetRefused => "target-refused",
+      ErrorResponse::TargetUnreachable => "target-unreachable",
+      ErrorResponse::TargetTimeout => "target-timeout",
+      ErrorResponse::TargetClosed => "target-closed",
+      ErrorResponse::ConnectFailed => "connect-failed",
+      ErrorResponse::InvalidResponse => "invalid-response",
+    }
+  }
+
+  pub fn message(self) -> &'static str {
+    match self {
+      ErrorResponse::InvalidRequest => "The request could not be parsed.",
+      ErrorResponse::InvalidContentLength => "The request Content-Length header is invalid.",
+      ErrorResponse::UnsupportedTransferEncoding => "The request Transfer-Encoding is not supported.",
+      ErrorResponse::InvalidRequestBody => "The request body framing is invalid.",
+      ErrorResponse::HeaderTooLarge => "The request head exceeds the size limit.",
+      ErrorResponse::UnsupportedMethod => "Only CONNECT requests are supported over HTTP/2.",
+      ErrorResponse::UnsupportedProtocol => "The extended CONNECT protocol is not supported.",
+      ErrorResponse::OrderExpired => "The order of this proxy expired.",
+      ErrorResponse::RequestsLimitReached => "Requests Limit Reached",
+      ErrorResponse::DnsNotFound => "The target host name does not resolve.",
+      ErrorResponse::DnsFailure => "The target host name could not be resolved.",
+      ErrorResponse::DnsTimeout => "The target host name resolution timed out.",
+      ErrorResponse::TargetRefused => "The target server refused the connection.",
+      ErrorResponse::TargetUnreachable => "The target server is unreachable.",
+      ErrorResponse::TargetTimeout => "The target server did not answer in time.",
+      ErrorResponse::TargetClosed => "The target server closed the connection.",
+      ErrorResponse::ConnectFailed => "The connection to the target server failed.",
+      ErrorResponse::InvalidResponse => "The target server sent an invalid response.",
+    }
+  }
+
+  /// Complete response closing the connection, the body is the short message of the error.
+  pub fn to_bytes(self) -> Vec<u8> {
+    let message = self.message();
+    format!(
+      "HTTP/1.1 {} {}\r\nX-Lampo-Error: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      self.status().0,
+      self.status().1,
+      self.code(),
+      message.len(),
+      message
+    )
+    .into_bytes()
+  }
+
+  /// Failures of the target connection, from its `io::ErrorKind`.
+  pub fn from_io(e: &std::io::Error) -> Self {
+    match e.kind() {
+      ErrorKind::ConnectionRefused => ErrorResponse::TargetRefused,
+      ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => ErrorResponse::TargetUnreachable,
+      ErrorKind::TimedOut => ErrorResponse::TargetTimeout,
+      ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => ErrorResponse::TargetClosed,
+      _ => ErrorResponse::ConnectFailed,
+    }
+  }
+
+  /// Failures of `DnsResolver::resolve`, which returns resolver errors or `AddrNotAvailable` without any record.
+  pub fn from_resolve(e: &(dyn std::error::Error + Send + Sync + 'static)) -> Self {
+    if let Some(e) = e.downcast_ref::<ResolveError>() {
+      return match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => ErrorResponse::DnsNotFound,
+        ResolveErrorKind::Timeout => ErrorResponse::DnsTimeout,
+        _ => ErrorResponse::DnsFailure,
+      };
+    }
+    match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+      Some(ErrorKind::AddrNotAvailable) => ErrorResponse::DnsNotFound,
+      Some(ErrorKind::TimedOut) => ErrorResponse::DnsTimeout,
+      _ => ErrorResponse::DnsFailure,
+    }
+  }
+
+  /// Invalid request heads, `None` when the client is gone or stalled and no response can be useful.
+  pub fn from_request(e: &HttpParserError) -> Option<Self> {
+    match e {
+      HttpParserError::StreamReadError(_) | HttpParserError::ClosedConnection | HttpParserError::StreamReadTimeout => None,
+      HttpParserError::HeaderTooLarge(_) | HttpParserError::BufferLimitExceeded => Some(ErrorResponse::HeaderTooLarge),
+      HttpParserError::InvalidContentLength => Some(ErrorResponse::InvalidContentLength),
+      HttpParserError::UnsupportedTransferEncoding => Some(ErrorResponse::UnsupportedTransferEncoding),
+      _ => Some(ErrorResponse::InvalidRequest),
+    }
+  }
+
+  /// Failed exchanges, answered only if the response head was not relayed yet.
+  pub fn from_exchange(e: &HttpHandlerError) -> Self {
+    match e {
+      HttpHandlerError::Request(e) if e.kind() == ErrorKind::InvalidData => ErrorResponse::InvalidRequestBody,
+      HttpHandlerError::Request(e) | HttpHandlerError::Relay(e) => ErrorResponse::from_io(e),
+      HttpHandlerError::Response(HttpParserError::ClosedConnection) => ErrorResponse::TargetClosed,
+      HttpHandlerError::Response(HttpParserError::StreamReadError(e)) => ErrorResponse::from_io(e),
+      HttpHandlerError::Response(_) => ErrorResponse::InvalidResponse,
+    }
+  }
+}
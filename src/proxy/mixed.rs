@@ -0,0 +1,82 @@
+use std::mem::drop;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::{Barrier, Semaphore};
+
+use crate::{
+  database::auth_manager::AuthManager,
+  dns::DnsResolver,
+  proxy::{
+    http,
+    socks5::{self, SocketState, SOCKS4_VERSION, SOCKS5_VERSION},
+  },
+  utils::{config::ProxyConfig, socket::make_listener},
+};
+
+/// Listener serving every protocol on a single port. SOCKS requests start with their version byte, which is never the
+/// first byte of an HTTP request line or of the HTTP/2 preface.
+#[derive(Clone)]
+pub struct MixedProxy {
+  listen_addr: SocketAddr,
+  auth_manager: AuthManager,
+  dns_resolver: DnsResolver,
+  config: Arc<ProxyConfig>,
+  barrier: Arc<Barrier>,
+  semaphore: Arc<Semaphore>,
+}
+
+impl MixedProxy {
+  pub fn new(
+    addr: SocketAddr,
+    auth_manager: AuthManager,
+    dns_resolver: DnsResolver,
+    config: Arc<ProxyConfig>,
+    barrier: Arc<Barrier>,
+    semaphore: Arc<Semaphore>,
+  ) -> Self {
+    Self {
+      listen_addr: addr,
+      auth_manager,
+      dns_resolver,
+      config,
+      barrier,
+      semaphore,
+    }
+  }
+
+  pub async fn listen(&self) {
+    let _permit = self.semaphore.acquire().await.expect("failed to acquire semaphore permit on preload");
+
+    let listener = match make_listener(self.listen_addr, self.config.backlog).await {
+      Ok(l) => l,
+      Err(e) => {
+        return error!("Failed to initialize MixedProxy listener. Err = {:?}", e);
+      }
+    };
+
+    drop(_permit);
+
+    let socket_state = SocketState::new(self.config.udp.max_sockets);
+
+    self.barrier.wait().await;
+
+    debug!("MixedProxy {} passed barrier, starting listener", self.listen_addr);
+
+    while let Ok((stream, client_addr)) = listener.accept().await {
+      let listen_addr = self.listen_addr;
+      let auth_manager = self.auth_manager.clone();
+      let dns_resolver = self.dns_resolver.clone();
+      let socket_state = socket_state.clone();
+      let config = self.config.clone();
+
+      tokio::spawn(async move {
+        match socks5::read_version(&stream, &config).await {
+          Ok(version @ (SOCKS4_VERSION | SOCKS5_VERSION)) => {
+            socks5::serve(stream, version, listen_addr, auth_manager, dns_resolver, socket_state, config).await
+          }
+          Ok(_) => http::serve(stream, client_addr, listen_addr, auth_manager, dns_resolver, config).await,
+          Err(e) => debug!("{}", e),
+        }
+      });
+    }
+  }
+}
@@ -6,12 +6,14 @@ use std::{
 use crate::{
   database::auth_manager::AuthManager,
   dns::DnsResolver,
-  proxy::{http::HttpProxy, socks5::Socks5Proxy},
+  proxy::{http::HttpProxy, mixed::MixedProxy, socks5::Socks5Proxy},
   utils::config::ProxyConfig,
 };
 use tokio::sync::{Barrier, Semaphore};
+use tokio_rustls::TlsAcceptor;
 
 mod http;
+mod mixed;
 mod socks5;
 
 #[derive(Clone)]
@@ -22,6 +24,7 @@ pub struct Proxy {
   config: ProxyConfig,
   auth_manager: AuthManager,
   dns_resolver: DnsResolver,
+  tls: Option<TlsAcceptor>,
 }
 
 impl Proxy {
@@ -32,6 +35,7 @@ impl Proxy {
     listen_addr: IpAddr,
     auth_manager: AuthManager,
     dns_resolver: DnsResolver,
+    tls: Option<TlsAcceptor>,
   ) -> Self {
     Self {
       barrier,
@@ -40,32 +44,80 @@ impl Proxy {
       config,
       auth_manager,
       dns_resolver,
+      tls,
     }
   }
   pub async fn listen(&self) {
-    let http_proxy = HttpProxy::new(
-      SocketAddr::from((self.listen_addr, self.config.ports.http)),
-      self.config.backlog,
-      self.auth_manager.clone(),
-      self.dns_resolver.clone(),
-      self.barrier.clone(),
-      self.semaphore.clone(),
-    );
-    let socks5_proxy = Socks5Proxy::new(
-      SocketAddr::from((self.listen_addr, self.config.ports.socks)),
-      self.config.backlog,
-      self.auth_manager.clone(),
-      self.dns_resolver.clone(),
-      self.config.udp.clone(),
-      self.barrier.clone(),
-      self.semaphore.clone(),
-    );
+    let config = Arc::new(self.config.clone());
+    let http_proxy = self.config.ports.http.map(|port| {
+      HttpProxy::new(
+        SocketAddr::from((self.listen_addr, port)),
+        self.auth_manager.clone(),
+        self.dns_resolver.clone(),
+        config.clone(),
+        None,
+        self.barrier.clone(),
+        self.semaphore.clone(),
+      )
+    });
+    let https_proxy = self.tls.clone().zip(self.config.ports.https).map(|(tls, port)| {
+      HttpProxy::new(
+        SocketAddr::from((self.listen_addr, port)),
+        self.auth_manager.clone(),
+        self.dns_resolver.clone(),
+        config.clone(),
+        Some(tls),
+        self.barrier.clone(),
+        self.semaphore.clone(),
+      )
+    });
+    let socks5_proxy = self.config.ports.socks.map(|port| {
+      Socks5Proxy::new(
+        SocketAddr::from((self.listen_addr, port)),
+        self.auth_manager.clone(),
+        self.dns_resolver.clone(),
+        config.clone(),
+        self.barrier.clone(),
+        self.semaphore.clone(),
+      )
+    });
+    let mixed_proxy = self.config.ports.mixed.map(|port| {
+      MixedProxy::new(
+        SocketAddr::from((self.listen_addr, port)),
+        self.auth_manager.clone(),
+        self.dns_resolver.clone(),
+        config.clone(),
+        self.barrier.clone(),
+        self.semaphore.clone(),
+      )
+    });
 
+    let ports = &self.config.ports;
     info!(
-      "Launched instance on {} (HTTP: {}, SOCKS5: {})",
-      self.listen_addr, self.config.ports.http, self.config.ports.socks
+      "Launched instance on {} (HTTP: {:?}, HTTPS: {:?}, SOCKS5: {:?}, Mixed: {:?})",
+      self.listen_addr, ports.http, ports.https, ports.socks, ports.mixed
     );
 
-    tokio::join!(http_proxy.listen(), socks5_proxy.listen(),);
+    let http_listen = async {
+      if let Some(http_proxy) = &http_proxy {
+        http_proxy.listen().await;
+      }
+    };
+    let https_listen = async {
+      if let Some(https_proxy) = &https_proxy {
+        https_proxy.listen().await;
+      }
+    };
+    let socks5_listen = async {
+      if let Some(socks5_proxy) = &socks5_proxy {
+        socks5_proxy.listen().await;
+      }
+    };
+    let mixed_listen = async {
+      if let Some(mixed_proxy) = &mixed_proxy {
+        mixed_proxy.listen().await;
+      }
+    };
+    tokio::join!(http_listen, https_listen, socks5_listen, mixed_listen);
   }
 }
@@ -1,9 +1,15 @@
 use std::net::SocketAddr;
 
 use socks5_proto::{Address, Reply};
-use tokio::{io::AsyncReadExt, net::UdpSocket};
+use tokio::{net::UdpSocket, time::sleep};
 
-use crate::proxy::socks5::utils::{association_socket::AssociationSocketHelper, error::Socks5HandlerError};
+use crate::{
+  proxy::socks5::utils::{
+    association_socket::{AssociationSocketHelper, ClientEndpoint},
+    error::Socks5HandlerError,
+  },
+  utils::idle::RelayTimeout,
+};
 
 use super::CommandHandler;
 
@@ -20,9 +26,17 @@ impl<'a> CommandHandler<'a> {
       Err(e) => return Err(Socks5HandlerError::BindUdpSocketError(e)),
     };
 
-    let mut socket_helper = AssociationSocketHelper::new(socket, self.dns_resolver.clone(), client_addr, self.udp_config.stale_ttl, 65535, 10000)
-      .await
-      .map_err(Socks5HandlerError::AssociationError)?;
+    let mut socket_helper = AssociationSocketHelper::new(
+      socket,
+      self.dns_resolver.clone(),
+      ClientEndpoint::new(&self.request.address, client_addr, self.config.udp.any_client_port),
+      &self.config.udp,
+      65535,
+      10000,
+      self.session.bandwidth.clone(),
+    )
+    .await
+    .map_err(Socks5HandlerError::AssociationError)?;
 
     self.reply(Reply::Succeeded, Address::SocketAddress(socket_addr)).await?;
 
@@ -34,26 +48,26 @@ impl<'a> CommandHandler<'a> {
           return Err(Socks5HandlerError::AssociationError(e));
         }
       }
-      _ = self.wait_close() => {
+      _ = CommandHandler::wait_close(self.stream) => {
         debug!("socket closed by either party, freeing socket and releasing count.");
         socket_helper.close();
         self.socket_state.decr();
       }
+      _ = self.session.terminated() => {
+        socket_helper.close();
+        self.socket_state.decr();
+        return Err(Socks5HandlerError::SessionTerminated);
+      }
+      _ = sleep(self.config.timeouts.max_lifetime) => {
+        socket_helper.close();
+        self.socket_state.decr();
+        return Err(Socks5HandlerError::RelayTimeout(RelayTimeout::Lifetime));
+      }
     };
 
     Ok(())
   }
 
-  async fn wait_close(&mut self) -> Result<(), tokio::io::Error> {
-    loop {
-      match self.stream.read(&mut [0]).await {
-        Ok(0) => break Ok(()),
-        Ok(_) => {}
-        Err(err) => break Err(err.into()),
-      }
-    }
-  }
-
   async fn bind_udp_socket(&self) -> Result<(UdpSocket, SocketAddr), tokio::io::Error> {
     let socket = UdpSocket::bind(self.bind_addr).await?;
     let socket_addr = socket.local_addr()?;
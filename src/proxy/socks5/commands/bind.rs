@@ -1,11 +1,88 @@
+use std::net::{IpAddr, SocketAddr};
+
 use socks5_proto::{Address, Reply};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  time::{timeout, Instant},
+};
 
-use crate::proxy::socks5::utils::error::Socks5HandlerError;
+use crate::{
+  proxy::socks5::utils::error::Socks5HandlerError,
+  utils::{
+    idle::{Activity, ActivityStream},
+    metered::MeteredStream,
+    socket::make_listener,
+  },
+};
 
 use super::CommandHandler;
 
 impl<'a> CommandHandler<'a> {
+  /// RFC 1928 BIND, listens on the egress address for a single inbound connection, replied to the client first with the
+  /// listening address and then with the address of the peer, before relaying it like a CONNECT.
   pub async fn bind(&mut self) -> Result<(), Socks5HandlerError> {
-    self.reply(Reply::CommandNotSupported, Address::unspecified()).await
+    let expected_peer = self.expected_peer().await?;
+    let deadline = Instant::now() + self.config.timeouts.max_lifetime;
+
+    let (listener, listener_addr) = match make_listener(self.bind_addr, 1).await.and_then(|listener| {
+      let addr = listener.local_addr()?;
+      Ok((listener, addr))
+    }) {
+      Ok(r) => r,
+      Err(e) => {
+        self.reply(Reply::GeneralFailure, Address::unspecified()).await?;
+        return Err(Socks5HandlerError::BindListenerError(e));
+      }
+    };
+
+    self.reply(Reply::Succeeded, Address::SocketAddress(listener_addr)).await?;
+
+    // The peer is given the connect timeout to show up, the client may give up by closing the control connection.
+    let accepted = tokio::select! {
+      accepted = timeout(self.config.timeouts.connect, CommandHandler::accept_peer(&listener, expected_peer)) => accepted,
+      _ = CommandHandler::wait_close(self.stream) => {
+        debug!("BIND cancelled by the client ({})", listener_addr);
+        return Ok(());
+      }
+      _ = self.session.terminated() => return Err(Socks5HandlerError::SessionTerminated),
+    };
+    drop(listener);
+
+    let (inbound, peer_addr) = match accepted {
+      Ok(Ok(r)) => r,
+      Ok(Err(e)) => {
+        self.reply(Reply::GeneralFailure, Address::unspecified()).await?;
+        return Err(Socks5HandlerError::BindAcceptError(listener_addr, e));
+      }
+      Err(_) => {
+        self.reply(Reply::TtlExpired, Address::unspecified()).await?;
+        return Err(Socks5HandlerError::BindAcceptTimeout(listener_addr));
+      }
+    };
+
+    self.reply(Reply::Succeeded, Address::SocketAddress(peer_addr)).await?;
+
+    let activity = Activity::new();
+    let mut inbound = ActivityStream::new(MeteredStream::new(inbound, self.session.bandwidth.clone()), activity.clone());
+    self.relay(&mut inbound, activity, deadline).await
+  }
+
+  /// Address the inbound connection must come from, none if the request address is unspecified. Only the IP is
+  /// checked, peers usually connect from an ephemeral port.
+  async fn expected_peer(&mut self) -> Result<Option<IpAddr>, Socks5HandlerError> {
+    match self.request.address {
+      Address::SocketAddress(addr) if addr.ip().is_unspecified() => Ok(None),
+      _ => Ok(Some(self.resolve_address().await?.ip())),
+    }
+  }
+
+  async fn accept_peer(listener: &TcpListener, expected_peer: Option<IpAddr>) -> Result<(TcpStream, SocketAddr), tokio::io::Error> {
+    loop {
+      let (stream, peer_addr) = listener.accept().await?;
+      match expected_peer {
+        Some(ip) if ip != peer_addr.ip() => debug!("rejected BIND connection from unexpected peer {} (expected {})", peer_addr, ip),
+        _ => return Ok((stream, peer_addr)),
+      }
+    }
   }
 }
@@ -1,6 +1,14 @@
 use socks5_proto::{Address, Reply};
+use tokio::time::{timeout, Instant};
 
-use crate::{proxy::socks5::utils::error::Socks5HandlerError, utils::socket::make_outbound};
+use crate::{
+  proxy::socks5::utils::error::Socks5HandlerError,
+  utils::{
+    idle::{Activity, ActivityStream},
+    metered::MeteredStream,
+    socket::make_outbound,
+  },
+};
 
 use super::CommandHandler;
 
@@ -10,21 +18,24 @@ impl<'a> CommandHandler<'a> {
 
     // let dns_start_time = Instant::now();
 
-    let mut outbound = match make_outbound(self.bind_addr, target_addr).await {
-      Ok(outbound) => {
+    let deadline = Instant::now() + self.config.timeouts.max_lifetime;
+    let activity = Activity::new();
+
+    let mut outbound = match timeout(self.config.timeouts.connect, make_outbound(self.bind_addr, target_addr)).await {
+      Ok(Ok(outbound)) => {
         // self.dns_resolver.record_latency(target_addr, dns_start_time.elapsed()).await;
         self.reply(Reply::Succeeded, Address::unspecified()).await?;
-        outbound
+        ActivityStream::new(MeteredStream::new(outbound, self.session.bandwidth.clone()), activity.clone())
       }
-      Err(e) => {
+      Ok(Err(e)) => {
         return Err(Socks5HandlerError::OutboundError(e, target_addr));
       }
+      Err(_) => {
+        self.reply(Reply::TtlExpired, Address::unspecified()).await?;
+        return Err(Socks5HandlerError::ConnectTimeout(target_addr));
+      }
     };
 
-    if let Err(e) = tokio::io::copy_bidirectional(&mut outbound, self.stream).await {
-      return Err(Socks5HandlerError::ClosedConnection(e));
-    }
-
-    Ok(())
+    self.relay(&mut outbound, activity, deadline).await
   }
 }
@@ -1,36 +1,84 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use socks5_proto::{Address, Command, Reply, Request, Response};
-use tokio::{net::TcpStream, time::timeout};
+use tokio::{
+  io::AsyncReadExt,
+  net::TcpStream,
+  time::{timeout, Instant},
+};
 
-use crate::{dns::DnsResolver, utils::config::ProxyConfigUdpSocket};
+use crate::{
+  cache::session::Session,
+  dns::DnsResolver,
+  utils::{
+    config::ProxyConfig,
+    idle::{Activity, ActivityStream},
+    metered::MeteredStream,
+  },
+};
 
-use super::{
-  handler::Socks5Handler,
-  utils::{error::Socks5HandlerError, socket_state::SocketState},
+use super::utils::{
+  error::Socks5HandlerError,
+  socket_state::SocketState,
+  socks4::{self, Socks4Request},
 };
 
 pub mod associate;
 pub mod bind;
 pub mod connect;
 
+/// Version of the SOCKS protocol spoken by the client, the replies are encoded accordingly.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SocksVersion {
+  V4,
+  V5,
+}
+
+pub struct CommandRequest {
+  pub version: SocksVersion,
+  pub command: Command,
+  pub address: Address,
+}
+
+impl From<Request> for CommandRequest {
+  fn from(request: Request) -> Self {
+    Self {
+      version: SocksVersion::V5,
+      command: request.command,
+      address: request.address,
+    }
+  }
+}
+
+impl From<Socks4Request> for CommandRequest {
+  fn from(request: Socks4Request) -> Self {
+    Self {
+      version: SocksVersion::V4,
+      command: request.command,
+      address: request.address,
+    }
+  }
+}
+
 pub struct CommandHandler<'a> {
   stream: &'a mut TcpStream,
-  request: Request,
+  request: CommandRequest,
   bind_addr: SocketAddr,
   dns_resolver: DnsResolver,
   socket_state: SocketState,
-  udp_config: ProxyConfigUdpSocket,
+  config: Arc<ProxyConfig>,
+  session: Session,
 }
 
 impl<'a> CommandHandler<'a> {
   pub fn new(
     stream: &'a mut TcpStream,
-    request: Request,
+    request: CommandRequest,
     bind_addr: SocketAddr,
     dns_resolver: DnsResolver,
     socket_state: SocketState,
-    udp_config: ProxyConfigUdpSocket,
+    config: Arc<ProxyConfig>,
+    session: Session,
   ) -> CommandHandler<'a> {
     CommandHandler {
       stream,
@@ -38,7 +86,8 @@ impl<'a> CommandHandler<'a> {
       bind_addr,
       dns_resolver,
       socket_state,
-      udp_config,
+      config,
+      session,
     }
   }
 
@@ -51,15 +100,44 @@ impl<'a> CommandHandler<'a> {
   }
 
   async fn reply(&mut self, reply: Reply, address: Address) -> Result<(), Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, Response::new(reply, address).write_to(self.stream)).await {
+    let result = match self.request.version {
+      SocksVersion::V4 => timeout(self.config.timeouts.handshake, socks4::write_reply(self.stream, reply, &address)).await,
+      SocksVersion::V5 => timeout(self.config.timeouts.handshake, Response::new(reply, address).write_to(self.stream)).await,
+    };
+    match result {
       Ok(req) => match req {
         Ok(req) => Ok(req),
-        Err(e) => Err(Socks5HandlerError::StreamReadError(e.into())),
+        Err(e) => Err(Socks5HandlerError::StreamReadError(e)),
       },
       Err(_) => Err(Socks5HandlerError::StreamReadTimeout),
     }
   }
 
+  /// Relays the client stream with the outbound one until either side closes, the idle timeout or the deadline.
+  async fn relay(&mut self, outbound: &mut ActivityStream<MeteredStream<TcpStream>>, activity: Activity, deadline: Instant) -> Result<(), Socks5HandlerError> {
+    tokio::select! {
+      result = activity.watch(tokio::io::copy_bidirectional(outbound, self.stream), self.config.timeouts.idle, deadline) => {
+        match result {
+          Ok(Err(e)) => Err(Socks5HandlerError::ClosedConnection(e)),
+          Err(e) => Err(Socks5HandlerError::RelayTimeout(e)),
+          Ok(Ok(_)) => Ok(()),
+        }
+      }
+      _ = self.session.terminated() => Err(Socks5HandlerError::SessionTerminated),
+    }
+  }
+
+  /// Waits for the client to close the control connection, the data it may send is discarded.
+  async fn wait_close(stream: &mut TcpStream) -> Result<(), tokio::io::Error> {
+    loop {
+      match stream.read(&mut [0]).await {
+        Ok(0) => break Ok(()),
+        Ok(_) => {}
+        Err(err) => break Err(err),
+      }
+    }
+  }
+
   async fn resolve_address(&mut self) -> Result<SocketAddr, Socks5HandlerError> {
     match self.request.address.clone() {
       Address::DomainAddress(domain, port) => {
@@ -1,14 +1,14 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc};
 
 use socks5_proto::{
   handshake::password::{Request as PasswordRequest, Response as PasswordResponse},
   handshake::{Method as HandshakeMethod, Request as HandshakeRequest, Response as HandshakeResponse},
-  Request,
+  Address, Reply, Request, Response,
 };
 
 use tokio::{net::TcpStream, time::timeout};
 
-use crate::{database::auth_manager::AuthManager, dns::DnsResolver, utils::config::ProxyConfigUdpSocket};
+use crate::{cache::auth::AuthCacheValue, database::auth_manager::AuthManager, dns::DnsResolver, utils::config::ProxyConfig};
 
 use super::{
   commands::CommandHandler,
@@ -21,19 +21,17 @@ pub struct Socks5Handler<'a> {
   auth_manager: AuthManager,
   dns_resolver: DnsResolver,
   socket_state: SocketState,
-  udp_config: ProxyConfigUdpSocket,
+  config: Arc<ProxyConfig>,
 }
 
 impl<'a> Socks5Handler<'a> {
-  pub const MAX_TIMEOUT: Duration = Duration::from_secs(10);
-
   pub fn new(
     stream: &'a mut TcpStream,
     listen_addr: SocketAddr,
     dns_resolver: DnsResolver,
     auth_manager: AuthManager,
     socket_state: SocketState,
-    udp_config: ProxyConfigUdpSocket,
+    config: Arc<ProxyConfig>,
   ) -> Socks5Handler<'a> {
     Socks5Handler {
       stream,
@@ -41,7 +39,7 @@ impl<'a> Socks5Handler<'a> {
       dns_resolver,
       auth_manager,
       socket_state,
-      udp_config,
+      config,
     }
   }
 
@@ -50,32 +48,42 @@ impl<'a> Socks5Handler<'a> {
       return warn!("{}", e);
     }
 
-    if let Err(e) = self.handle_authentication().await {
-      match e {
-        Socks5HandlerError::AuthenticationError => return debug!("{}", e),
-        _ => return warn!("{}", e),
-      }
-    }
+    let cache_value = match self.handle_authentication().await {
+      Ok(cv) => cv,
+      Err(e @ Socks5HandlerError::AuthenticationError) => return debug!("{}", e),
+      Err(e) => return warn!("{}", e),
+    };
 
     let request = match self.request_read().await {
       Ok(r) => r,
       Err(e) => return warn!("{}", e),
     };
 
+    if !self.auth_manager.try_consume_request(&cache_value) {
+      if let Err(e) = self.request_reply(Reply::ConnectionNotAllowed).await {
+        return warn!("{}", e);
+      }
+      return debug!("{}", Socks5HandlerError::RequestsLimitReached(cache_value.order_id.clone()));
+    }
+
     let bind_addr = SocketAddr::new(self.listen_addr.ip(), 0);
 
     if let Err(e) = CommandHandler::new(
-      &mut self.stream,
-      request,
+      self.stream,
+      request.into(),
       bind_addr,
       self.dns_resolver.clone(),
       self.socket_state.clone(),
-      self.udp_config.clone(),
+      self.config.clone(),
+      self.auth_manager.open_session(&cache_value, self.listen_addr.ip()),
     )
     .execute()
     .await
     {
-      return warn!("{}", e);
+      match e {
+        Socks5HandlerError::SessionTerminated | Socks5HandlerError::RelayTimeout(_) | Socks5HandlerError::BindAcceptTimeout(_) => debug!("{}", e),
+        _ => warn!("{}", e),
+      }
     }
   }
 
@@ -89,7 +97,7 @@ impl<'a> Socks5Handler<'a> {
     Ok(())
   }
 
-  async fn handle_authentication(&mut self) -> Result<(), Socks5HandlerError> {
+  async fn handle_authentication(&mut self) -> Result<Arc<AuthCacheValue>, Socks5HandlerError> {
     let cache_value = match self.auth_manager.get_or_fetch_and_insert(&self.listen_addr).await {
       Some(cv) => cv,
       None => {
@@ -109,29 +117,33 @@ impl<'a> Socks5Handler<'a> {
         return Err(Socks5HandlerError::AuthenticationError);
       }
 
-      if !self.auth_manager.check_credentials(cache_value, username.unwrap(), password.unwrap()) {
+      if !self
+        .auth_manager
+        .check_credentials(cache_value.clone(), username.unwrap(), password.unwrap())
+        .await
+      {
         self.handshake_password_reply(false).await?;
         return Err(Socks5HandlerError::AuthenticationError);
       }
 
       self.handshake_password_reply(true).await?;
-      return Ok(());
+      return Ok(cache_value);
     }
 
     let client_addr = self.stream.peer_addr().map_err(Socks5HandlerError::PeerAddrError)?;
 
-    if self.auth_manager.check_whitelist(cache_value, client_addr) {
+    if self.auth_manager.check_whitelist(cache_value.clone(), client_addr) {
       self.handshake_reply(HandshakeMethod::NONE).await?;
     } else {
       self.handshake_reply(HandshakeMethod::UNACCEPTABLE).await?;
       return Err(Socks5HandlerError::AuthenticationError);
     }
 
-    Ok(())
+    Ok(cache_value)
   }
 
   async fn handshake_reply(&mut self, method: HandshakeMethod) -> Result<(), Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, HandshakeResponse::new(method).write_to(self.stream)).await {
+    match timeout(self.config.timeouts.handshake, HandshakeResponse::new(method).write_to(self.stream)).await {
       Ok(result) => {
         if let Err(e) = result {
           Err(Socks5HandlerError::StreamWriteError(e))
@@ -144,7 +156,7 @@ impl<'a> Socks5Handler<'a> {
   }
 
   async fn handshake_read(&mut self) -> Result<HandshakeRequest, Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, HandshakeRequest::read_from(self.stream)).await {
+    match timeout(self.config.timeouts.handshake, HandshakeRequest::read_from(self.stream)).await {
       Ok(req) => match req {
         Ok(req) => Ok(req),
         Err(e) => Err(Socks5HandlerError::StreamReadError(e.into())),
@@ -154,7 +166,7 @@ impl<'a> Socks5Handler<'a> {
   }
 
   async fn handshake_password_read(&mut self) -> Result<PasswordRequest, Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, PasswordRequest::read_from(self.stream)).await {
+    match timeout(self.config.timeouts.handshake, PasswordRequest::read_from(self.stream)).await {
       Ok(req) => match req {
         Ok(req) => Ok(req),
         Err(e) => Err(Socks5HandlerError::StreamReadError(e.into())),
@@ -164,7 +176,7 @@ impl<'a> Socks5Handler<'a> {
   }
 
   async fn handshake_password_reply(&mut self, authorized: bool) -> Result<(), Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, PasswordResponse::new(authorized).write_to(self.stream)).await {
+    match timeout(self.config.timeouts.handshake, PasswordResponse::new(authorized).write_to(self.stream)).await {
       Ok(result) => {
         if let Err(e) = result {
           Err(Socks5HandlerError::StreamWriteError(e))
@@ -176,8 +188,20 @@ impl<'a> Socks5Handler<'a> {
     }
   }
 
+  async fn request_reply(&mut self, reply: Reply) -> Result<(), Socks5HandlerError> {
+    match timeout(
+      self.config.timeouts.handshake,
+      Response::new(reply, Address::unspecified()).write_to(self.stream),
+    )
+    .await
+    {
+      Ok(result) => result.map_err(Socks5HandlerError::StreamWriteError),
+      Err(_) => Err(Socks5HandlerError::StreamWriteTimeout),
+    }
+  }
+
   async fn request_read(&mut self) -> Result<Request, Socks5HandlerError> {
-    match timeout(Socks5Handler::MAX_TIMEOUT, Request::read_from(self.stream)).await {
+    match timeout(self.config.timeouts.handshake, Request::read_from(self.stream)).await {
       Ok(req) => match req {
         Ok(req) => Ok(req),
         Err(e) => Err(Socks5HandlerError::StreamReadError(e.into())),
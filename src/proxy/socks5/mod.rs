@@ -1,29 +1,38 @@
 mod commands;
 mod handler;
+mod socks4_handler;
 mod utils;
 
 use std::mem::drop;
-use tokio::sync::{Barrier, Semaphore};
+use tokio::{
+  net::TcpStream,
+  sync::{Barrier, Semaphore},
+  time::timeout,
+};
 
 use crate::{
   database::auth_manager::AuthManager,
   dns::DnsResolver,
-  proxy::socks5::handler::Socks5Handler,
-  utils::{config::ProxyConfigUdpSocket, socket::make_listener},
+  proxy::socks5::{handler::Socks5Handler, socks4_handler::Socks4Handler},
+  utils::{config::ProxyConfig, socket::make_listener},
 };
 use std::{net::SocketAddr, sync::Arc};
 
-use self::utils::socket_state::SocketState;
+use self::utils::error::Socks5HandlerError;
+
+pub use self::utils::{
+  socket_state::SocketState,
+  socks4::{SOCKS4_VERSION, SOCKS5_VERSION},
+};
 
 // use self::utils::association_socket::UdpHelper;
 
 #[derive(Clone)]
 pub struct Socks5Proxy {
   listen_addr: SocketAddr,
-  backlog: u32,
   auth_manager: AuthManager,
   dns_resolver: DnsResolver,
-  udp_config: ProxyConfigUdpSocket,
+  config: Arc<ProxyConfig>,
   barrier: Arc<Barrier>,
   semaphore: Arc<Semaphore>,
 }
@@ -31,19 +40,17 @@ pub struct Socks5Proxy {
 impl Socks5Proxy {
   pub fn new(
     addr: SocketAddr,
-    backlog: u32,
     auth_manager: AuthManager,
     dns_resolver: DnsResolver,
-    udp_config: ProxyConfigUdpSocket,
+    config: Arc<ProxyConfig>,
     barrier: Arc<Barrier>,
     semaphore: Arc<Semaphore>,
   ) -> Self {
     Self {
       listen_addr: addr,
-      backlog,
       auth_manager,
       dns_resolver,
-      udp_config,
+      config,
       barrier,
       semaphore,
     }
@@ -52,7 +59,7 @@ impl Socks5Proxy {
   pub async fn listen(&self) {
     let _permit = self.semaphore.acquire().await.expect("failed to acquire semaphore permit on preload");
 
-    let listener = match make_listener(self.listen_addr, self.backlog).await {
+    let listener = match make_listener(self.listen_addr, self.config.backlog).await {
       Ok(l) => l,
       Err(e) => {
         return error!("Failed to initialize Socks5Proxy listener. Err = {:?}", e);
@@ -61,24 +68,61 @@ impl Socks5Proxy {
 
     drop(_permit);
 
-    let socket_state = SocketState::new(self.udp_config.max_sockets);
+    let socket_state = SocketState::new(self.config.udp.max_sockets);
 
     self.barrier.wait().await;
 
     debug!("Socks5Proxy {} passed barrier, starting listener", self.listen_addr);
 
-    while let Ok((mut stream, _)) = listener.accept().await {
-      let listen_addr = self.listen_addr.clone();
+    while let Ok((stream, _)) = listener.accept().await {
+      let listen_addr = self.listen_addr;
       let auth_manager = self.auth_manager.clone();
       let dns_resolver = self.dns_resolver.clone();
       let socket_state = socket_state.clone();
-      let udp_config = self.udp_config.clone();
+      let config = self.config.clone();
 
       tokio::spawn(async move {
-        Socks5Handler::new(&mut stream, listen_addr, dns_resolver, auth_manager, socket_state, udp_config)
-          .execute()
-          .await;
+        match read_version(&stream, &config).await {
+          Ok(version) => serve(stream, version, listen_addr, auth_manager, dns_resolver, socket_state, config).await,
+          Err(e) => debug!("{}", e),
+        }
       });
     }
   }
 }
+
+/// Serves a SOCKS client connection with the handler of its protocol version.
+pub async fn serve(
+  mut stream: TcpStream,
+  version: u8,
+  listen_addr: SocketAddr,
+  auth_manager: AuthManager,
+  dns_resolver: DnsResolver,
+  socket_state: SocketState,
+  config: Arc<ProxyConfig>,
+) {
+  match version {
+    SOCKS5_VERSION => {
+      Socks5Handler::new(&mut stream, listen_addr, dns_resolver, auth_manager, socket_state, config)
+        .execute()
+        .await
+    }
+    SOCKS4_VERSION => {
+      Socks4Handler::new(&mut stream, listen_addr, dns_resolver, auth_manager, socket_state, config)
+        .execute()
+        .await
+    }
+    version => debug!("{}", Socks5HandlerError::UnsupportedVersion(version)),
+  }
+}
+
+/// Peeks the version byte, left in the stream for the handler of the version to read its request.
+pub async fn read_version(stream: &TcpStream, config: &ProxyConfig) -> Result<u8, Socks5HandlerError> {
+  let mut version = [0u8; 1];
+  match timeout(config.timeouts.handshake, stream.peek(&mut version)).await {
+    Ok(Ok(0)) => Err(Socks5HandlerError::ClosedConnection(std::io::ErrorKind::UnexpectedEof.into())),
+    Ok(Ok(_)) => Ok(version[0]),
+    Ok(Err(e)) => Err(Socks5HandlerError::StreamReadError(e)),
+    Err(_) => Err(Socks5HandlerError::StreamReadTimeout),
+  }
+}
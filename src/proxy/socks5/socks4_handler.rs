@@ -0,0 +1,126 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use socks5_proto::{Address, Reply};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{cache::auth::AuthCacheValue, database::auth_manager::AuthManager, dns::DnsResolver, utils::config::ProxyConfig};
+
+use super::{
+  commands::CommandHandler,
+  utils::{
+    error::Socks5HandlerError,
+    socket_state::SocketState,
+    socks4::{self, Socks4Request},
+  },
+};
+
+/// Handles SOCKS4 and SOCKS4a clients, CONNECT and BIND only. The protocol has no password, orders using credentials
+/// expect `username:password` in the USERID field, the others are checked against their whitelist.
+pub struct Socks4Handler<'a> {
+  stream: &'a mut TcpStream,
+  listen_addr: SocketAddr,
+  auth_manager: AuthManager,
+  dns_resolver: DnsResolver,
+  socket_state: SocketState,
+  config: Arc<ProxyConfig>,
+}
+
+impl<'a> Socks4Handler<'a> {
+  pub fn new(
+    stream: &'a mut TcpStream,
+    listen_addr: SocketAddr,
+    dns_resolver: DnsResolver,
+    auth_manager: AuthManager,
+    socket_state: SocketState,
+    config: Arc<ProxyConfig>,
+  ) -> Socks4Handler<'a> {
+    Socks4Handler {
+      stream,
+      listen_addr,
+      dns_resolver,
+      auth_manager,
+      socket_state,
+      config,
+    }
+  }
+
+  pub async fn execute(&mut self) {
+    let request = match self.request_read().await {
+      Ok(r) => r,
+      Err(e) => return warn!("{}", e),
+    };
+
+    let cache_value = match self.handle_authentication(&request.userid).await {
+      Ok(cv) => cv,
+      Err(e) => {
+        if let Err(e) = self.request_reply(Reply::ConnectionNotAllowed).await {
+          return warn!("{}", e);
+        }
+        return debug!("{}", e);
+      }
+    };
+
+    if !self.auth_manager.try_consume_request(&cache_value) {
+      if let Err(e) = self.request_reply(Reply::ConnectionNotAllowed).await {
+        return warn!("{}", e);
+      }
+      return debug!("{}", Socks5HandlerError::RequestsLimitReached(cache_value.order_id.clone()));
+    }
+
+    let bind_addr = SocketAddr::new(self.listen_addr.ip(), 0);
+
+    if let Err(e) = CommandHandler::new(
+      self.stream,
+      request.into(),
+      bind_addr,
+      self.dns_resolver.clone(),
+      self.socket_state.clone(),
+      self.config.clone(),
+      self.auth_manager.open_session(&cache_value, self.listen_addr.ip()),
+    )
+    .execute()
+    .await
+    {
+      match e {
+        Socks5HandlerError::SessionTerminated | Socks5HandlerError::RelayTimeout(_) | Socks5HandlerError::BindAcceptTimeout(_) => debug!("{}", e),
+        _ => warn!("{}", e),
+      }
+    }
+  }
+
+  async fn handle_authentication(&mut self, userid: &[u8]) -> Result<Arc<AuthCacheValue>, Socks5HandlerError> {
+    let cache_value = match self.auth_manager.get_or_fetch_and_insert(&self.listen_addr).await {
+      Some(cv) => cv,
+      None => return Err(Socks5HandlerError::AuthenticationError),
+    };
+
+    let authorized = if cache_value.use_credentials {
+      match std::str::from_utf8(userid).ok().and_then(|userid| userid.split_once(':')) {
+        Some((username, password)) => self.auth_manager.check_credentials(cache_value.clone(), username, password).await,
+        None => false,
+      }
+    } else {
+      let client_addr = self.stream.peer_addr().map_err(Socks5HandlerError::PeerAddrError)?;
+      self.auth_manager.check_whitelist(cache_value.clone(), client_addr)
+    };
+
+    if !authorized {
+      return Err(Socks5HandlerError::AuthenticationError);
+    }
+    Ok(cache_value)
+  }
+
+  async fn request_reply(&mut self, reply: Reply) -> Result<(), Socks5HandlerError> {
+    match timeout(self.config.timeouts.handshake, socks4::write_reply(self.stream, reply, &Address::unspecified())).await {
+      Ok(result) => result.map_err(Socks5HandlerError::StreamWriteError),
+      Err(_) => Err(Socks5HandlerError::StreamWriteTimeout),
+    }
+  }
+
+  async fn request_read(&mut self) -> Result<Socks4Request, Socks5HandlerError> {
+    match timeout(self.config.timeouts.handshake, Socks4Request::read_from(self.stream)).await {
+      Ok(req) => req,
+      Err(_) => Err(Socks5HandlerError::StreamReadTimeout),
+    }
+  }
+}
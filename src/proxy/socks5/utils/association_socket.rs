@@ -1,13 +1,67 @@
-use crate::dns::DnsResolver;
+use crate::{cache::usage::BandwidthCounter, dns::DnsResolver, utils::config::ProxyConfigUdpSocket};
 use bytes::{Buf, Bytes, BytesMut};
 use moka::future::{Cache, CacheBuilder};
 use socks5_proto::{Address, UdpHeader};
 use std::io::Cursor;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
 
-use super::error::AssociationSocketError;
+use super::{error::AssociationSocketError, reassembly::ReassemblyQueue};
+
+/// UDP endpoint the client sends its datagrams from, declared in the ASSOCIATE request. Datagrams are only accepted
+/// from the IP of the control connection: a different declared IP, e.g. the private address of a NATed client, is
+/// ignored along with its port. Otherwise a declared port restricts the client to it. Without a usable port, the first
+/// valid datagram pins it, unless every source port is explicitly allowed.
+pub struct ClientEndpoint {
+  ip: IpAddr,
+  port: Option<u16>,
+  any_port: bool,
+}
+
+impl ClientEndpoint {
+  pub fn new(declared: &Address, peer_addr: SocketAddr, any_port: bool) -> Self {
+    let peer_ip = peer_addr.ip().to_canonical();
+    let (ip, port) = match declared {
+      Address::SocketAddress(addr) => (addr.ip().to_canonical(), addr.port()),
+      Address::DomainAddress(_, port) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), *port),
+    };
+    let port = if ip.is_unspecified() || ip == peer_ip {
+      port
+    } else {
+      debug!("UDP ASSOCIATE declared {} differs from client {}, ignoring its port", ip, peer_ip);
+      0
+    };
+    Self {
+      ip: peer_ip,
+      port: (port != 0).then_some(port),
+      any_port,
+    }
+  }
+
+  /// Whether the datagram comes from the client.
+  fn matches(&self, src_addr: SocketAddr) -> bool {
+    src_addr.ip().to_canonical() == self.ip && self.port.is_none_or(|port| port == src_addr.port())
+  }
+
+  /// Pins the client port to the source of its first valid datagram, if neither declared nor left open.
+  fn learn(&mut self, src_addr: SocketAddr) {
+    if self.port.is_none() && !self.any_port {
+      debug!("learned UDP ASSOCIATE client endpoint {}", src_addr);
+      self.port = Some(src_addr.port());
+    }
+  }
+}
+
+enum Datagram {
+  // Client -> Proxy, the header is stripped from the packet.
+  FromClient(Bytes, UdpHeader, SocketAddr),
+  // Target -> Proxy, from one of the flows of the client, along with the client source of the flow.
+  FromTarget(Bytes, SocketAddr, SocketAddr),
+  // Neither from the client nor from one of its flows.
+  Unexpected(SocketAddr),
+}
 
 pub struct AssociationSocketHelper {
   socket: UdpSocket,
@@ -15,27 +69,34 @@ pub struct AssociationSocketHelper {
   max_capacity: usize,
   dns_resolver: DnsResolver,
   buffer: BytesMut,
-  target_cache: Cache<SocketAddr, SocketAddr>,
-  client_addr: SocketAddr,
+  // Targets the client sent datagrams to, mapped to the client source which last did. Only they may answer, their
+  // datagrams are relayed to that source, each flow until idle for the socket TTL.
+  flows: Cache<SocketAddr, SocketAddr>,
+  client: ClientEndpoint,
+  bandwidth: Arc<BandwidthCounter>,
+  reassembly: ReassemblyQueue,
 }
 
 impl AssociationSocketHelper {
   pub async fn new(
     socket: UdpSocket,
     dns_resolver: DnsResolver,
-    client_addr: SocketAddr,
-    socket_ttl: Duration,
+    client: ClientEndpoint,
+    udp_config: &ProxyConfigUdpSocket,
     max_capacity: usize,
     cache_size: u64,
+    bandwidth: Arc<BandwidthCounter>,
   ) -> Result<Self, AssociationSocketError> {
     let helper = AssociationSocketHelper {
       socket,
-      socket_ttl,
-      client_addr,
+      socket_ttl: udp_config.stale_ttl,
+      client,
       max_capacity,
       dns_resolver,
       buffer: BytesMut::with_capacity(max_capacity),
-      target_cache: CacheBuilder::new(cache_size).build(),
+      flows: CacheBuilder::new(cache_size).time_to_idle(udp_config.stale_ttl).build(),
+      bandwidth,
+      reassembly: ReassemblyQueue::new(udp_config.reassembly),
     };
     Ok(helper)
   }
@@ -52,41 +113,48 @@ impl AssociationSocketHelper {
   }
 
   async fn handle_recv_from(&mut self) -> Result<(), AssociationSocketError> {
-    match self.recv_from().await {
+    match self.recv_from().await? {
       // Client -> Proxy -> Server
-      // Make sure that header.frag is 0, fragmentation is not supported.
-      Ok(((pkt, Some(header), dest), _)) if header.frag == 0 => {
+      Datagram::FromClient(pkt, header, dest) => {
         debug!(
-          "received UDP socket message. PKT LEN = {}, HEADER LEN = {}, DEST = {}",
+          "received UDP socket message. PKT LEN = {}, HEADER LEN = {}, DEST = {}, FRAG = {}",
           pkt.len(),
           header.serialized_len(),
           dest,
+          header.frag,
         );
 
-        if let Err(e) = self.send_to(pkt, &None, dest).await {
-          warn!("error while sending to server during UDP ASSOCIATE. Dst = {}, Err = {}", dest, e);
+        // Fragments are held until their sequence is reassembled, the target receives a single datagram.
+        let pkt = if header.frag == 0 {
+          pkt
+        } else {
+          match self.reassembly.push(header.frag, dest, pkt) {
+            Some(datagram) => datagram,
+            None => return Ok(()),
+          }
+        };
+
+        let pkt_len = pkt.len() as u64;
+        match self.send_to(pkt, &None, dest).await {
+          Ok(_) => self.bandwidth.add_out(pkt_len),
+          Err(e) => warn!("error while sending to server during UDP ASSOCIATE. Dst = {}, Err = {}", dest, e),
         }
       }
       // Server -> Proxy -> Client
-      Ok(((pkt, None, dest), _)) => {
-        debug!("sent UDP socket message. PKT LEN = {}, DEST = {}", pkt.len(), dest);
+      Datagram::FromTarget(pkt, src_addr, client_addr) => {
+        debug!("sent UDP socket message. PKT LEN = {}, SRC = {}, DEST = {}", pkt.len(), src_addr, client_addr);
 
-        let header = UdpHeader::new(0, Address::SocketAddress(dest));
-        if let Err(e) = self.send_to(pkt, &Some(header), dest).await {
-          warn!("error while sending to client during UDP ASSOCIATE. Dst = {}, Err = {}", dest, e);
+        // Received from the target, accounted even if relaying to the client fails.
+        self.bandwidth.add_in(pkt.len() as u64);
+
+        let header = UdpHeader::new(0, Address::SocketAddress(src_addr));
+        if let Err(e) = self.send_to(pkt, &Some(header), client_addr).await {
+          warn!("error while sending to client during UDP ASSOCIATE. Dst = {}, Err = {}", client_addr, e);
         }
       }
-      // Frag pkt, ignore.
-      Ok(((_, Some(header), dest), _)) if header.frag != 0 => {
-        warn!(
-          "fragmented packet received and ignored during UDP ASSOCIATE. Dst = {}, Frag = {}",
-          dest, header.frag
-        );
-      }
-      Ok(_) => {
-        debug!("Unknown UDP socket event received, please check further");
+      Datagram::Unexpected(src_addr) => {
+        debug!("dropped UDP datagram from unexpected source {} during UDP ASSOCIATE", src_addr);
       }
-      Err(e) => return Err(e),
     };
     Ok(())
   }
@@ -94,10 +162,16 @@ impl AssociationSocketHelper {
   // UdpSocket recv_from implementation to match SOCKS5 UDP ASSOCIATE use case:
   // Client (PKT + S5_UDP_HEADER) -> PROXY (checks and strips S5_UDP_HEADER from PKT) -> Server (PKT)
   // Server (PKT) -> PROXY (adds S5_UDP_HEADER) -> Client (PKT + UDP_HEADER)
-  async fn recv_from(&mut self) -> Result<((Bytes, Option<UdpHeader>, SocketAddr), SocketAddr), AssociationSocketError> {
+  async fn recv_from(&mut self) -> Result<Datagram, AssociationSocketError> {
     self.buffer.resize(self.max_capacity, 0); // Initialize buffer for recv_from.
 
-    let (len, src_addr) = self.socket.recv_from(&mut self.buffer).await.map_err(AssociationSocketError::SocketReadError)?;
+    // Incomplete fragment sequences are discarded on time, not only when the next fragment arrives.
+    let (len, src_addr) = loop {
+      tokio::select! {
+        result = self.socket.recv_from(&mut self.buffer) => break result.map_err(AssociationSocketError::SocketReadError)?,
+        _ = self.reassembly.expired() => (),
+      }
+    };
 
     if len > self.max_capacity {
       return Err(AssociationSocketError::RecvBufferOverflow(len, self.max_capacity));
@@ -105,19 +179,12 @@ impl AssociationSocketHelper {
 
     self.buffer.truncate(len);
 
-    // Request comes from the server because the src_addr matches a target_cache entry.
-    if let Some(client_addr) = self.target_cache.get(&src_addr) {
-      // Check for intruders.
-      if client_addr.ip() != self.client_addr.ip() {
-        return Err(AssociationSocketError::SrcAddrMismatchSTCError(self.client_addr, client_addr));
-      }
-      return Ok(((self.buffer.copy_to_bytes(self.buffer.len()), None, client_addr), src_addr));
-    }
-    // Request comes from the client, target_cache does not match.
-
-    // Check for intruders.
-    if src_addr.ip() != self.client_addr.ip() {
-      return Err(AssociationSocketError::SrcAddrMismatchCTSError(self.client_addr, src_addr));
+    if !self.client.matches(src_addr) {
+      // Datagram from a target the client sent to, anything else is dropped.
+      return Ok(match self.flows.get(&src_addr) {
+        Some(client_addr) => Datagram::FromTarget(self.buffer.copy_to_bytes(self.buffer.len()), src_addr, client_addr),
+        None => Datagram::Unexpected(src_addr),
+      });
     }
 
     let header = UdpHeader::read_from(&mut Cursor::new(&self.buffer))
@@ -125,6 +192,7 @@ impl AssociationSocketHelper {
       .map_err(AssociationSocketError::UdpHeaderParseError)?;
 
     let pkt = self.buffer.split_off(header.serialized_len()).freeze();
+    self.client.learn(src_addr);
 
     // This is the target server address
     let header_addr = match &header.address {
@@ -140,9 +208,9 @@ impl AssociationSocketHelper {
     };
 
     // Inserting <TargetServerAddr, ClientAddr>
-    self.target_cache.insert(header_addr, src_addr).await;
+    self.flows.insert(header_addr, src_addr).await;
 
-    Ok(((pkt, Some(header), header_addr), src_addr))
+    Ok(Datagram::FromClient(pkt, header, header_addr))
   }
 
   async fn send_to<P: AsRef<[u8]>>(&mut self, pkt: P, header: &Option<UdpHeader>, addr: SocketAddr) -> Result<usize, AssociationSocketError> {
@@ -1,6 +1,8 @@
 use std::{io::Error as IoError, net::SocketAddr, time::Duration};
 use thiserror::Error;
 
+use crate::utils::idle::RelayTimeout;
+
 #[allow(dead_code)]
 #[derive(Error, Debug)]
 pub enum Socks5HandlerError {
@@ -14,8 +16,26 @@ pub enum Socks5HandlerError {
   StreamReadError(IoError),
   #[error("error while writing from stream. Err = {0}")]
   StreamWriteError(IoError),
+  #[error("unsupported SOCKS version {0}")]
+  UnsupportedVersion(u8),
+  #[error("invalid SOCKS4 request, {0}")]
+  InvalidSocks4Request(String),
   #[error("error or invalid credentials while authenticating")]
   AuthenticationError,
+  #[error("requests limit reached for order {0}")]
+  RequestsLimitReached(String),
+  #[error("session terminated, order deleted, expired or changed")]
+  SessionTerminated,
+  #[error("timeout while connecting to outbound ({0})")]
+  ConnectTimeout(SocketAddr),
+  #[error("failed to listen for BIND connection. Err = {0}")]
+  BindListenerError(IoError),
+  #[error("failed to accept BIND connection ({0}). Err = {1}")]
+  BindAcceptError(SocketAddr, IoError),
+  #[error("timeout while waiting for BIND connection ({0})")]
+  BindAcceptTimeout(SocketAddr),
+  #[error("relay closed, {0}")]
+  RelayTimeout(RelayTimeout),
   #[error("connection closed by the client/upstream. Err = {0}")]
   ClosedConnection(IoError),
   #[error("stream read timeout")]
@@ -50,10 +70,6 @@ pub enum AssociationSocketError {
   SocketWriteError(IoError),
   #[error("stale udp socket reached time-to-live limit ({0:?})")]
   SocketTTLError(Duration),
-  #[error("Client -> Server, TCP client addr did not match UDP socket client addr. (TCP: {0}, UDP: {1})")]
-  SrcAddrMismatchCTSError(SocketAddr, SocketAddr),
-  #[error("Server -> Client, TCP client addr did not match UDP socket client addr. (TCP: {0}, UDP: {1})")]
-  SrcAddrMismatchSTCError(SocketAddr, SocketAddr),
   // #[error("failed to get bind address from underlying UdpSocket. Err = {0}")]
   // BindAddrNotAvailable(IoError),
 }
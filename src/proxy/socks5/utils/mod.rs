@@ -1,3 +1,5 @@
 pub mod association_socket;
 pub mod error;
+pub mod reassembly;
 pub mod socket_state;
+pub mod socks4;
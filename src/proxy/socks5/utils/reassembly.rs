@@ -0,0 +1,211 @@
+use std::net::SocketAddr;
+
+use bytes::{Bytes, BytesMut};
+use tokio::time::Instant;
+
+use crate::utils::config::UdpReassemblyConfig;
+
+/// High bit of the FRAG field, set on the last fragment of a sequence.
+const END_OF_SEQUENCE: u8 = 0x80;
+
+/// RFC 1928 reassembly queue of a UDP ASSOCIATE, holding the fragments of a single sequence at a time. Fragments must
+/// arrive in increasing position order, an older or repeated position abandons the sequence being reassembled.
+pub struct ReassemblyQueue {
+  config: UdpReassemblyConfig,
+  // Fragments of the current sequence, in position order.
+  fragments: Vec<Bytes>,
+  size: usize,
+  // Highest position processed for the current sequence, 0 when the queue is empty.
+  position: u8,
+  target: Option<SocketAddr>,
+  // Start of the reassembly timer, set by the first fragment of the sequence.
+  started: Instant,
+}
+
+impl ReassemblyQueue {
+  pub fn new(config: UdpReassemblyConfig) -> Self {
+    Self {
+      config,
+      fragments: Vec::new(),
+      size: 0,
+      position: 0,
+      target: None,
+      started: Instant::now(),
+    }
+  }
+
+  /// Queues a fragment (`frag != 0`), returns the reassembled datagram once the end of its sequence is received.
+  pub fn push(&mut self, frag: u8, target: SocketAddr, pkt: Bytes) -> Option<Bytes> {
+    let position = frag & !END_OF_SEQUENCE;
+    if position == 0 {
+      warn!("invalid UDP fragment position received during UDP ASSOCIATE. Dst = {}, Frag = {}", target, frag);
+      return None;
+    }
+
+    if self.position != 0 {
+      if self.started.elapsed() >= self.config.timeout {
+        debug!(
+          "UDP fragment sequence reassembly timed out. Dst = {:?}, Fragments = {}",
+          self.target,
+          self.fragments.len()
+        );
+        self.reset();
+      } else if position <= self.position || self.target != Some(target) {
+        debug!(
+          "UDP fragment sequence abandoned. Dst = {:?}, Last = {}, Received = {} ({})",
+          self.target, self.position, position, target
+        );
+        self.reset();
+      }
+    }
+
+    if self.size + pkt.len() > self.config.max_size {
+      warn!(
+        "UDP fragment sequence exceeds the reassembly maximum size and is discarded ({}/{}). Dst = {}",
+        self.size + pkt.len(),
+        self.config.max_size,
+        target
+      );
+      self.reset();
+      return None;
+    }
+
+    if self.position == 0 {
+      self.started = Instant::now();
+      self.target = Some(target);
+    }
+    self.position = position;
+    self.size += pkt.len();
+    self.fragments.push(pkt);
+
+    if frag & END_OF_SEQUENCE == 0 {
+      return None;
+    }
+
+    // Positions are increasing, the sequence is complete if none of them was skipped.
+    if self.fragments.len() != position as usize {
+      debug!("UDP fragment sequence ended with missing fragments. Dst = {}, Last = {}", target, position);
+      self.reset();
+      return None;
+    }
+    let mut datagram = BytesMut::with_capacity(self.size);
+    for fragment in &self.fragments {
+      datagram.extend_from_slice(fragment);
+    }
+    self.reset();
+    Some(datagram.freeze())
+  }
+
+  /// Resolves once the sequence being reassembled times out, discarding it. Pending while the queue is empty.
+  pub async fn expired(&mut self) {
+    if self.position == 0 {
+      return std::future::pending().await;
+    }
+    tokio::time::sleep_until(self.started + self.config.timeout).await;
+    debug!(
+      "UDP fragment sequence reassembly timed out. Dst = {:?}, Fragments = {}",
+      self.target,
+      self.fragments.len()
+    );
+    self.reset();
+  }
+
+  fn reset(&mut self) {
+    self.fragments.clear();
+    self.size = 0;
+    self.position = 0;
+    self.target = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  fn queue(max_size: usize) -> ReassemblyQueue {
+    ReassemblyQueue::new(UdpReassemblyConfig {
+      timeout: Duration::from_millis(50),
+      max_size,
+    })
+  }
+
+  fn target() -> SocketAddr {
+    "192.0.2.1:53".parse().unwrap()
+  }
+
+  #[test]
+  fn reassembles_in_order_sequence() {
+    let mut queue = queue(1024);
+    assert_eq!(queue.push(1, target(), Bytes::from_static(b"ab")), None);
+    assert_eq!(queue.push(2, target(), Bytes::from_static(b"cd")), None);
+    assert_eq!(
+      queue.push(3 | END_OF_SEQUENCE, target(), Bytes::from_static(b"ef")).as_deref(),
+      Some(&b"abcdef"[..])
+    );
+    assert_eq!(queue.position, 0);
+  }
+
+  #[test]
+  fn out_of_order_fragment_abandons_sequence() {
+    let mut queue = queue(1024);
+    queue.push(1, target(), Bytes::from_static(b"ab"));
+    queue.push(2, target(), Bytes::from_static(b"cd"));
+    // An older position starts a new sequence, which then misses its first fragment.
+    assert_eq!(queue.push(1, target(), Bytes::from_static(b"xy")), None);
+    assert_eq!(
+      queue.push(2 | END_OF_SEQUENCE, target(), Bytes::from_static(b"z")).as_deref(),
+      Some(&b"xyz"[..])
+    );
+
+    queue.push(1, target(), Bytes::from_static(b"ab"));
+    queue.push(2, target(), Bytes::from_static(b"cd"));
+    assert_eq!(queue.push(2 | END_OF_SEQUENCE, target(), Bytes::from_static(b"cd")), None);
+  }
+
+  #[test]
+  fn fragment_for_another_target_abandons_sequence() {
+    let mut queue = queue(1024);
+    queue.push(1, target(), Bytes::from_static(b"ab"));
+    let other = "192.0.2.2:53".parse().unwrap();
+    assert_eq!(queue.push(2 | END_OF_SEQUENCE, other, Bytes::from_static(b"cd")), None);
+    assert_eq!(queue.position, 0);
+  }
+
+  #[test]
+  fn missing_fragment_discards_sequence() {
+    let mut queue = queue(1024);
+    queue.push(1, target(), Bytes::from_static(b"ab"));
+    assert_eq!(queue.push(3 | END_OF_SEQUENCE, target(), Bytes::from_static(b"ef")), None);
+    assert_eq!(queue.position, 0);
+    assert!(queue.fragments.is_empty());
+  }
+
+  #[test]
+  fn oversized_sequence_is_discarded() {
+    let mut queue = queue(4);
+    queue.push(1, target(), Bytes::from_static(b"abc"));
+    assert_eq!(queue.push(2 | END_OF_SEQUENCE, target(), Bytes::from_static(b"de")), None);
+    assert_eq!(queue.size, 0);
+    assert_eq!(queue.position, 0);
+  }
+
+  #[test]
+  fn position_zero_is_rejected() {
+    let mut queue = queue(1024);
+    assert_eq!(queue.push(END_OF_SEQUENCE, target(), Bytes::from_static(b"ab")), None);
+    assert_eq!(queue.position, 0);
+  }
+
+  #[tokio::test]
+  async fn expired_discards_stale_sequence() {
+    let mut queue = queue(1024);
+    assert!(tokio::time::timeout(Duration::from_millis(100), queue.expired()).await.is_err());
+
+    queue.push(1, target(), Bytes::from_static(b"ab"));
+    tokio::time::timeout(Duration::from_secs(1), queue.expired()).await.unwrap();
+    assert_eq!(queue.position, 0);
+    assert!(queue.fragments.is_empty());
+  }
+}
@@ -0,0 +1,143 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use socks5_proto::{Address, Command, Reply};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::error::Socks5HandlerError;
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const SOCKS5_VERSION: u8 = 0x05;
+
+const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_BIND: u8 = 0x02;
+
+const REPLY_GRANTED: u8 = 90;
+const REPLY_REJECTED: u8 = 91;
+
+// Longest USERID or SOCKS4a hostname accepted, both are null terminated.
+const MAX_FIELD_LEN: usize = 255;
+
+/// SOCKS4 request, SOCKS4a when the destination IP is 0.0.0.x and a hostname follows the USERID.
+pub struct Socks4Request {
+  pub command: Command,
+  pub address: Address,
+  pub userid: Vec<u8>,
+}
+
+impl Socks4Request {
+  pub async fn read_from<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, Socks5HandlerError> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await.map_err(Socks5HandlerError::StreamReadError)?;
+    if buf[0] != SOCKS4_VERSION {
+      return Err(Socks5HandlerError::UnsupportedVersion(buf[0]));
+    }
+
+    let command = match buf[1] {
+      COMMAND_CONNECT => Command::Connect,
+      COMMAND_BIND => Command::Bind,
+      command => return Err(Socks5HandlerError::InvalidSocks4Request(format!("unknown command {}", command))),
+    };
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    let userid = read_null_terminated(stream).await?;
+
+    let octets = ip.octets();
+    let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+      Address::DomainAddress(read_null_terminated(stream).await?, port)
+    } else {
+      Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    };
+
+    Ok(Self { command, address, userid })
+  }
+}
+
+async fn read_null_terminated<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, Socks5HandlerError> {
+  let mut field = Vec::new();
+  loop {
+    match stream.read_u8().await.map_err(Socks5HandlerError::StreamReadError)? {
+      0 => return Ok(field),
+      _ if field.len() == MAX_FIELD_LEN => return Err(Socks5HandlerError::InvalidSocks4Request(String::from("field too long"))),
+      byte => field.push(byte),
+    }
+  }
+}
+
+/// Writes a SOCKS4 reply, which only tells whether the request is granted. The address is only meaningful for BIND,
+/// IPv6 addresses and hostnames cannot be represented and are sent unspecified.
+pub async fn write_reply<W: AsyncWrite + Unpin>(stream: &mut W, reply: Reply, address: &Address) -> std::io::Result<()> {
+  let mut buf = [0u8; 8];
+  buf[1] = if reply == Reply::Succeeded { REPLY_GRANTED } else { REPLY_REJECTED };
+  if let Address::SocketAddress(SocketAddr::V4(addr)) = address {
+    buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+    buf[4..8].copy_from_slice(&addr.ip().octets());
+  }
+  stream.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn read(request: &[u8]) -> Result<Socks4Request, Socks5HandlerError> {
+    Socks4Request::read_from(&mut &request[..]).await
+  }
+
+  #[tokio::test]
+  async fn reads_socks4_connect() {
+    let request = read(b"\x04\x01\x00\x50\xc0\x00\x02\x01user\x00").await.unwrap();
+    assert!(matches!(request.command, Command::Connect));
+    assert_eq!(request.userid, b"user");
+    assert!(matches!(request.address, Address::SocketAddress(addr) if addr == "192.0.2.1:80".parse().unwrap()));
+  }
+
+  #[tokio::test]
+  async fn reads_socks4a_hostname() {
+    let request = read(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00").await.unwrap();
+    assert!(request.userid.is_empty());
+    assert!(matches!(request.address, Address::DomainAddress(ref host, 443) if host == b"example.com"));
+  }
+
+  #[tokio::test]
+  async fn unspecified_ip_is_not_socks4a() {
+    let request = read(b"\x04\x02\x00\x50\x00\x00\x00\x00\x00").await.unwrap();
+    assert!(matches!(request.command, Command::Bind));
+    assert!(matches!(request.address, Address::SocketAddress(addr) if addr == "0.0.0.0:80".parse().unwrap()));
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_requests() {
+    assert!(matches!(
+      read(b"\x05\x01\x00\x50\xc0\x00\x02\x01\x00").await,
+      Err(Socks5HandlerError::UnsupportedVersion(5))
+    ));
+    assert!(matches!(
+      read(b"\x04\x03\x00\x50\xc0\x00\x02\x01\x00").await,
+      Err(Socks5HandlerError::InvalidSocks4Request(_))
+    ));
+    // Missing hostname terminator.
+    assert!(matches!(
+      read(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example").await,
+      Err(Socks5HandlerError::StreamReadError(_))
+    ));
+
+    let mut request = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+    request.extend([b'a'; MAX_FIELD_LEN + 1]);
+    request.push(0);
+    assert!(matches!(read(&request).await, Err(Socks5HandlerError::InvalidSocks4Request(_))));
+  }
+
+  #[tokio::test]
+  async fn writes_reply() {
+    let mut buf = Vec::new();
+    let address = Address::SocketAddress("192.0.2.1:80".parse().unwrap());
+    write_reply(&mut buf, Reply::Succeeded, &address).await.unwrap();
+    assert_eq!(buf, b"\x00\x5a\x00\x50\xc0\x00\x02\x01");
+
+    buf.clear();
+    write_reply(&mut buf, Reply::GeneralFailure, &Address::DomainAddress(b"example.com".to_vec(), 80))
+      .await
+      .unwrap();
+    assert_eq!(buf, b"\x00\x5b\x00\x00\x00\x00\x00\x00");
+  }
+}
@@ -1,15 +1,26 @@
 use base64::engine::general_purpose;
 use base64::Engine;
 
-pub fn parse_credentials(header_value: &str) -> Option<(String, String)> {
-  const PREFIX: &str = "Basic ";
+use super::digest::DigestCredentials;
 
-  if !header_value.starts_with(PREFIX) {
-    return None;
-  }
+/// Credentials of a `Proxy-Authorization` header, by scheme.
+pub enum ProxyAuthorization {
+  Basic(String, String),
+  Digest(DigestCredentials),
+  Bearer(String),
+}
 
-  let encoded = &header_value[PREFIX.len()..];
-  extract_credentials(encoded)
+pub fn parse_authorization(header_value: &str) -> Option<ProxyAuthorization> {
+  let (scheme, params) = header_value.trim().split_once(' ')?;
+  match scheme.to_ascii_lowercase().as_str() {
+    "basic" => extract_credentials(params.trim()).map(|(username, password)| ProxyAuthorization::Basic(username, password)),
+    "digest" => DigestCredentials::parse(params).map(ProxyAuthorization::Digest),
+    "bearer" => {
+      let token = params.trim();
+      (!token.is_empty()).then(|| ProxyAuthorization::Bearer(token.to_string()))
+    }
+    _ => None,
+  }
 }
 
 fn extract_credentials(encoded: &str) -> Option<(String, String)> {
@@ -1,9 +1,12 @@
 use config::{Config, ConfigError};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
+use super::header_rule::HeaderRule;
+
 pub fn parse_args() -> Option<String> {
   let args: Vec<String> = env::args().collect();
 
@@ -21,14 +24,42 @@ pub fn parse_args() -> Option<String> {
 
 pub fn load_config(path: String) -> Result<GlobalConfig, ConfigError> {
   let config = Config::builder().add_source(config::File::with_name(&path)).build()?;
-  config.try_deserialize::<GlobalConfig>()
+  let mut config = config.try_deserialize::<GlobalConfig>()?;
+  config.proxy.http.resolve_products();
+
+  for (slug, product) in &config.proxy.http.products {
+    if let Some(rule) = product.header_rules.iter().find(|rule| !rule.is_valid()) {
+      return Err(ConfigError::Message(format!("invalid header rule {:?} for product {}", rule, slug)));
+    }
+  }
+
+  let ports = &config.proxy.ports;
+  if ports.http.is_none() && ports.https.is_none() && ports.socks.is_none() && ports.mixed.is_none() {
+    return Err(ConfigError::Message(String::from(
+      "at least one of proxy.ports.http, https, socks or mixed must be set",
+    )));
+  }
+
+  if config.proxy.udp.reassembly.timeout < Duration::from_secs(5) {
+    return Err(ConfigError::Message(String::from("proxy.udp.reassembly.timeout must be at least 5 seconds")));
+  }
+
+  if config.usage.flush_interval.is_zero() {
+    return Err(ConfigError::Message(String::from("usage.flush_interval must not be zero")));
+  }
+
+  Ok(config)
 }
 
 #[derive(Clone, Deserialize)]
 pub struct GlobalConfig {
   pub proxy: ProxyConfig,
   pub cache: CacheConfigContainer,
-  pub mongodb: MongoDBConfig,
+  #[serde(default)]
+  pub auth: AuthConfig,
+  pub mongodb: Option<MongoDBConfig>,
+  #[serde(default)]
+  pub usage: UsageConfig,
   pub log4rs: Log4rsConfig,
 }
 
@@ -36,12 +67,47 @@ pub struct GlobalConfig {
 pub struct CacheConfigContainer {
   pub dns: DnsCacheConfig,
   pub auth: AuthCacheConfig,
+  #[serde(default)]
+  pub credentials: CredentialsCacheConfig,
+  #[serde(default)]
+  pub nonces: NoncesCacheConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct AuthConfig {
+  #[serde(default)]
+  pub backend: AuthBackendType,
+  pub path: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackendType {
+  #[default]
+  MongoDB,
+  File,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct UsageConfig {
+  #[serde(with = "humantime_serde")]
+  pub flush_interval: Duration,
+}
+
+impl Default for UsageConfig {
+  fn default() -> Self {
+    Self {
+      flush_interval: Duration::from_secs(10),
+    }
+  }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct MongoDBConfig {
   pub uri: String,
   pub database: String,
+  // Directory the change streams resume tokens are persisted to, streams start from now if unset.
+  pub resume_token_path: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -50,6 +116,156 @@ pub struct ProxyConfig {
   pub preload: ProxyConfigPreload,
   pub backlog: u32,
   pub udp: ProxyConfigUdpSocket,
+  #[serde(default)]
+  pub http: ProxyConfigHttp,
+  #[serde(default)]
+  pub timeouts: ProxyConfigTimeouts,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfigTimeouts {
+  // Establishing the connection to the target.
+  #[serde(with = "humantime_serde")]
+  pub connect: Duration,
+  // Each step of the client handshake, the SOCKS5 negotiation or the first HTTP request head.
+  #[serde(with = "humantime_serde")]
+  pub handshake: Duration,
+  // Relayed connections without traffic in either direction, and keep-alive HTTP connections between requests.
+  #[serde(with = "humantime_serde")]
+  pub idle: Duration,
+  // Whatever the traffic, client connections are closed past it.
+  #[serde(with = "humantime_serde")]
+  pub max_lifetime: Duration,
+}
+
+impl Default for ProxyConfigTimeouts {
+  fn default() -> Self {
+    Self {
+      connect: Duration::from_secs(10),
+      handshake: Duration::from_secs(10),
+      idle: Duration::from_secs(300),
+      max_lifetime: Duration::from_secs(86400),
+    }
+  }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfigHttp {
+  // Maximum size of a request line and headers, larger requests are answered with 431.
+  pub max_header_size: usize,
+  // Concurrent streams allowed on an HTTP/2 connection, each one may relay to its own target.
+  pub http2_max_streams: u32,
+  pub forwarding: HttpForwardingConfig,
+  pub auth: HttpAuthConfig,
+  // Settings overrides by order product slug.
+  pub products: HashMap<String, HttpProductConfig>,
+}
+
+impl ProxyConfigHttp {
+  pub fn forwarding(&self, product: &str) -> &HttpForwardingConfig {
+    self.products.get(product).map_or(&self.forwarding, |product| &product.forwarding)
+  }
+
+  pub fn auth(&self, product: &str) -> &HttpAuthConfig {
+    self.products.get(product).map_or(&self.auth, |product| &product.auth)
+  }
+
+  pub fn header_rules(&self, product: &str) -> &[HeaderRule] {
+    self.products.get(product).map_or(&[], |product| &product.header_rules)
+  }
+
+  /// Fills the settings left unset by the products with the global ones.
+  fn resolve_products(&mut self) {
+    for product in self.products.values_mut() {
+      product.forwarding = HttpForwardingConfig {
+        via: product.via.unwrap_or(self.forwarding.via),
+        forwarded_for: product.forwarded_for.unwrap_or(self.forwarding.forwarded_for),
+      };
+      product.auth = HttpAuthConfig {
+        realm: product.realm.clone().unwrap_or_else(|| self.auth.realm.clone()),
+        schemes: product.schemes.clone().unwrap_or_else(|| self.auth.schemes.clone()),
+      };
+    }
+  }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct HttpProductConfig {
+  // Unset settings fall back to the global [proxy.http.forwarding] and [proxy.http.auth] ones.
+  via: Option<bool>,
+  forwarded_for: Option<ForwardedFor>,
+  realm: Option<String>,
+  schemes: Option<Vec<HttpAuthScheme>>,
+  // Request header rewrites for every order of the product, applied before the order own rules.
+  #[serde(default)]
+  pub header_rules: Vec<HeaderRule>,
+  // Effective settings of the product, resolved once the configuration is loaded.
+  #[serde(skip)]
+  pub forwarding: HttpForwardingConfig,
+  #[serde(skip)]
+  pub auth: HttpAuthConfig,
+}
+
+impl Default for ProxyConfigHttp {
+  fn default() -> Self {
+    Self {
+      max_header_size: 16384,
+      http2_max_streams: 100,
+      forwarding: HttpForwardingConfig::default(),
+      auth: HttpAuthConfig::default(),
+      products: HashMap::new(),
+    }
+  }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HttpForwardingConfig {
+  // Appends the proxy to the Via header.
+  pub via: bool,
+  pub forwarded_for: ForwardedFor,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpAuthConfig {
+  pub realm: String,
+  // Accepted Proxy-Authorization schemes, each one is offered in the 407 challenge.
+  pub schemes: Vec<HttpAuthScheme>,
+}
+
+impl Default for HttpAuthConfig {
+  fn default() -> Self {
+    Self {
+      realm: String::from("Leastslow Network"),
+      schemes: vec![HttpAuthScheme::Basic],
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpAuthScheme {
+  Basic,
+  /// RFC 7616, only for orders storing plaintext passwords.
+  Digest,
+  /// Opaque API tokens of the order.
+  Bearer,
+}
+
+/// Handling of the X-Forwarded-For and Forwarded headers.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedFor {
+  /// Forwards the headers sent by the client untouched.
+  #[default]
+  Keep,
+  /// Removes the headers sent by the client, the target cannot tell a proxy is used.
+  Strip,
+  /// Appends the client address to the headers.
+  Add,
 }
 
 #[derive(Clone, Deserialize)]
@@ -57,12 +273,47 @@ pub struct ProxyConfigUdpSocket {
   #[serde(with = "humantime_serde")]
   pub stale_ttl: Duration,
   pub max_sockets: usize,
+  #[serde(default)]
+  pub reassembly: UdpReassemblyConfig,
+  // Accepts datagrams from every source port of the client IP when the ASSOCIATE request declares none, for NATed
+  // clients using several ports. Otherwise the port is pinned by the first datagram.
+  #[serde(default)]
+  pub any_client_port: bool,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct UdpReassemblyConfig {
+  // Incomplete fragment sequences are discarded past it, RFC 1928 requires at least 5 seconds.
+  #[serde(with = "humantime_serde")]
+  pub timeout: Duration,
+  // Maximum size of a reassembled datagram, larger sequences are discarded.
+  pub max_size: usize,
+}
+
+impl Default for UdpReassemblyConfig {
+  fn default() -> Self {
+    Self {
+      timeout: Duration::from_secs(5),
+      max_size: 65507,
+    }
+  }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct ProxyConfigPorts {
-  pub http: u16,
-  pub socks: u16,
+  pub http: Option<u16>,
+  pub socks: Option<u16>,
+  // Serves HTTP, SOCKS4 and SOCKS5 on a single port, detected from the first byte of each connection.
+  pub mixed: Option<u16>,
+  // HTTP proxy over TLS, requires the PEM certificate chain and private key paths.
+  pub https: Option<u16>,
+  pub tls_cert: Option<String>,
+  pub tls_key: Option<String>,
+  // Offers HTTP/2 with ALPN on the TLS listener. Only CONNECT is proxied over HTTP/2, off unless every client
+  // negotiating it tunnels through CONNECT.
+  #[serde(default)]
+  pub tls_http2: bool,
 }
 
 #[derive(Clone, Deserialize)]
@@ -77,6 +328,48 @@ pub struct AuthCacheConfig {
   pub max_size: u64,
   #[serde(with = "humantime_serde")]
   pub time_to_live: Duration,
+  // How long an address without order is remembered as such.
+  #[serde(with = "humantime_serde", default = "AuthCacheConfig::default_negative_ttl")]
+  pub negative_ttl: Duration,
+}
+
+impl AuthCacheConfig {
+  fn default_negative_ttl() -> Duration {
+    Duration::from_secs(30)
+  }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CredentialsCacheConfig {
+  pub max_size: u64,
+  #[serde(with = "humantime_serde")]
+  pub time_to_live: Duration,
+}
+
+impl Default for CredentialsCacheConfig {
+  fn default() -> Self {
+    Self {
+      max_size: 10000,
+      time_to_live: Duration::from_secs(300),
+    }
+  }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct NoncesCacheConfig {
+  pub max_size: u64,
+  // Lifetime of the Digest authentication nonces.
+  #[serde(with = "humantime_serde")]
+  pub time_to_live: Duration,
+}
+
+impl Default for NoncesCacheConfig {
+  fn default() -> Self {
+    Self {
+      max_size: 100000,
+      time_to_live: Duration::from_secs(300),
+    }
+  }
 }
 
 #[derive(Clone, Deserialize)]
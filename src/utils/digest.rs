@@ -0,0 +1,194 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DigestAlgorithm {
+  Md5,
+  Sha256,
+}
+
+impl DigestAlgorithm {
+  pub fn name(self) -> &'static str {
+    match self {
+      DigestAlgorithm::Md5 => "MD5",
+      DigestAlgorithm::Sha256 => "SHA-256",
+    }
+  }
+
+  fn hash(self, data: &str) -> String {
+    match self {
+      DigestAlgorithm::Md5 => hex::encode(Md5::digest(data.as_bytes())),
+      DigestAlgorithm::Sha256 => hex::encode(Sha256::digest(data.as_bytes())),
+    }
+  }
+}
+
+/// Parameters of a Digest `Proxy-Authorization` header, see RFC 7616 section 3.4.
+#[derive(Clone, Debug)]
+pub struct DigestCredentials {
+  pub username: String,
+  pub realm: String,
+  pub nonce: String,
+  pub uri: String,
+  pub response: String,
+  pub algorithm: DigestAlgorithm,
+  pub qop: Option<String>,
+  /// Nonce count, incremented by the client on every request using the nonce.
+  pub nc: Option<u32>,
+  pub cnonce: Option<String>,
+}
+
+impl DigestCredentials {
+  /// Parses the header parameters following the `Digest` scheme name.
+  pub fn parse(params: &str) -> Option<Self> {
+    let mut username = None;
+    let mut realm = None;
+    let mut nonce = None;
+    let mut uri = None;
+    let mut response = None;
+    let mut algorithm = DigestAlgorithm::Md5;
+    let mut qop = None;
+    let mut nc = None;
+    let mut cnonce = None;
+
+    for (name, value) in split_params(params) {
+      match name.to_ascii_lowercase().as_str() {
+        "username" => username = Some(value),
+        "realm" => realm = Some(value),
+        "nonce" => nonce = Some(value),
+        "uri" => uri = Some(value),
+        "response" => response = Some(value.to_ascii_lowercase()),
+        "algorithm" => {
+          algorithm = match value.to_ascii_uppercase().as_str() {
+            "MD5" => DigestAlgorithm::Md5,
+            "SHA-256" => DigestAlgorithm::Sha256,
+            // Session variants and SHA-512-256 are not offered in our challenges.
+            _ => return None,
+          }
+        }
+        "qop" => qop = Some(value),
+        "nc" => nc = Some(u32::from_str_radix(&value, 16).ok()?),
+        "cnonce" => cnonce = Some(value),
+        _ => (),
+      }
+    }
+
+    Some(Self {
+      username: username?,
+      realm: realm?,
+      nonce: nonce?,
+      uri: uri?,
+      response: response?,
+      algorithm,
+      qop,
+      nc,
+      cnonce,
+    })
+  }
+
+  /// Computes the expected response for the plaintext password and the request method.
+  pub fn expected_response(&self, password: &str, method: &str) -> Option<String> {
+    let ha1 = self.algorithm.hash(&format!("{}:{}:{}", self.username, self.realm, password));
+    let ha2 = self.algorithm.hash(&format!("{}:{}", method, self.uri));
+    match self.qop.as_deref() {
+      Some("auth") => {
+        let nc = self.nc?;
+        let cnonce = self.cnonce.as_deref()?;
+        Some(self.algorithm.hash(&format!("{}:{}:{:08x}:{}:auth:{}", ha1, self.nonce, nc, cnonce, ha2)))
+      }
+      // Only qop=auth is offered, the RFC 2069 compatibility mode has no replay protection.
+      _ => None,
+    }
+  }
+}
+
+/// Splits comma separated `name=value` pairs, values being tokens or quoted strings.
+fn split_params(params: &str) -> Vec<(String, String)> {
+  let mut pairs = Vec::new();
+  let mut chars = params.chars().peekable();
+  loop {
+    while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+    let name: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+    if name.is_empty() {
+      break;
+    }
+    if chars.next_if_eq(&'=').is_none() {
+      continue;
+    }
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    let mut value = String::new();
+    if chars.next_if_eq(&'"').is_some() {
+      while let Some(c) = chars.next() {
+        match c {
+          '\\' => value.extend(chars.next()),
+          '"' => break,
+          c => value.push(c),
+        }
+      }
+    } else {
+      value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ',')));
+    }
+    pairs.push((name.trim().to_string(), value.trim().to_string()));
+  }
+  pairs
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // RFC 7616 section 3.9.1, user Mufasa with the password "Circle of Life".
+  const RFC_PARAMS: &str = r#"username="Mufasa", realm="http-auth@example.org", uri="/dir/index.html",
+    algorithm=ALGORITHM, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", nc=00000001,
+    cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth, response="RESPONSE",
+    opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+
+  fn rfc_credentials(algorithm: &str, response: &str) -> DigestCredentials {
+    DigestCredentials::parse(&RFC_PARAMS.replace("ALGORITHM", algorithm).replace("RESPONSE", response)).unwrap()
+  }
+
+  #[test]
+  fn parses_parameters() {
+    let credentials = rfc_credentials("MD5", "8CA523F5E9506FED4657C9700EEBDBEC");
+    assert_eq!(credentials.username, "Mufasa");
+    assert_eq!(credentials.realm, "http-auth@example.org");
+    assert_eq!(credentials.uri, "/dir/index.html");
+    assert_eq!(credentials.algorithm, DigestAlgorithm::Md5);
+    assert_eq!(credentials.qop.as_deref(), Some("auth"));
+    assert_eq!(credentials.nc, Some(1));
+    assert_eq!(credentials.response, "8ca523f5e9506fed4657c9700eebdbec");
+  }
+
+  #[test]
+  fn rfc_7616_md5_example() {
+    let credentials = rfc_credentials("MD5", "8ca523f5e9506fed4657c9700eebdbec");
+    assert_eq!(credentials.expected_response("Circle of Life", "GET").unwrap(), credentials.response);
+  }
+
+  #[test]
+  fn rfc_7616_sha256_example() {
+    let credentials = rfc_credentials("SHA-256", "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+    assert_eq!(credentials.expected_response("Circle of Life", "GET").unwrap(), credentials.response);
+    assert_ne!(credentials.expected_response("Circle of life", "GET").unwrap(), credentials.response);
+  }
+
+  #[test]
+  fn quoted_values_keep_commas_and_escapes() {
+    let credentials = DigestCredentials::parse(r#"username="a\"b,c", realm="r", nonce=n, uri="/?a=1,2", response=00"#).unwrap();
+    assert_eq!(credentials.username, "a\"b,c");
+    assert_eq!(credentials.uri, "/?a=1,2");
+  }
+
+  #[test]
+  fn rejects_incomplete_or_unsupported_parameters() {
+    assert!(DigestCredentials::parse(r#"username="u", realm="r", nonce="n", uri="/""#).is_none());
+    assert!(DigestCredentials::parse(r#"username="u", realm="r", nonce="n", uri="/", response="0", algorithm=MD5-sess"#).is_none());
+    assert!(DigestCredentials::parse(r#"username="u", realm="r", nonce="n", uri="/", response="0", nc=zz"#).is_none());
+  }
+
+  #[test]
+  fn requires_qop_auth() {
+    let credentials = DigestCredentials::parse(r#"username="u", realm="r", nonce="n", uri="/", response="0""#).unwrap();
+    assert!(credentials.expected_response("p", "GET").is_none());
+  }
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+/// Rewrite of a request header on plain HTTP traffic, set by the order or its product.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum HeaderRule {
+  /// Replaces every field with the name by a single one.
+  Set {
+    name: String,
+    value: String,
+  },
+  Remove {
+    name: String,
+  },
+  /// Adds a value to a comma separated list header, creating it if missing.
+  Append {
+    name: String,
+    value: String,
+  },
+}
+
+/// Headers deciding the message framing or routing, or handled by the proxy itself, never rewritten.
+const PROTECTED: [&str; 10] = [
+  "host",
+  "content-length",
+  "transfer-encoding",
+  "connection",
+  "proxy-connection",
+  "keep-alive",
+  "te",
+  "trailer",
+  "upgrade",
+  "proxy-authorization",
+];
+
+impl HeaderRule {
+  pub fn name(&self) -> &str {
+    match self {
+      HeaderRule::Set { name, .. } | HeaderRule::Remove { name } | HeaderRule::Append { name, .. } => name,
+    }
+  }
+
+  /// Whether the rule can be applied, its name must be a valid token outside of the protected headers and its value
+  /// must not break out of the header line.
+  pub fn is_valid(&self) -> bool {
+    let name = self.name();
+    let valid_value = match self {
+      HeaderRule::Set { value, .. } | HeaderRule::Append { value, .. } => http::HeaderValue::from_str(value).is_ok(),
+      HeaderRule::Remove { .. } => true,
+    };
+    valid_value && http::HeaderName::from_bytes(name.as_bytes()).is_ok() && !PROTECTED.contains(&name.to_ascii_lowercase().as_str())
+  }
+}
+
+/// Keeps the applicable rules of an order. Invalid rules are reported and skipped.
+pub fn valid_rules(key: &str, rules: &[HeaderRule]) -> Vec<HeaderRule> {
+  rules
+    .iter()
+    .filter(|rule| {
+      let valid = rule.is_valid();
+      if !valid {
+        warn!("invalid header rule {:?} for {}, ignored", rule, key);
+      }
+      valid
+    })
+    .cloned()
+    .collect()
+}
@@ -0,0 +1,111 @@
+use std::{
+  future::Future,
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  task::{Context, Poll},
+  time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  time::Instant,
+};
+
+#[derive(Error, Debug)]
+pub enum RelayTimeout {
+  #[error("no traffic for {0:?}")]
+  Idle(Duration),
+  #[error("connection reached its max lifetime")]
+  Lifetime,
+}
+
+/// Last time data went through the streams sharing it.
+#[derive(Clone)]
+pub struct Activity {
+  start: Instant,
+  // Milliseconds elapsed since `start` at the last transfer.
+  last: Arc<AtomicU64>,
+}
+
+impl Activity {
+  pub fn new() -> Self {
+    Self {
+      start: Instant::now(),
+      last: Arc::new(AtomicU64::new(0)),
+    }
+  }
+
+  pub fn touch(&self) {
+    self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+  }
+
+  pub fn last(&self) -> Instant {
+    self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+  }
+
+  /// Runs the future until it completes, no data went through the tracked streams for `idle`, or `deadline` is reached.
+  pub async fn watch<F: Future>(&self, future: F, idle: Duration, deadline: Instant) -> Result<F::Output, RelayTimeout> {
+    self.touch();
+    tokio::pin!(future);
+    loop {
+      let idle_deadline = self.last() + idle;
+      tokio::select! {
+        output = &mut future => return Ok(output),
+        _ = tokio::time::sleep_until(idle_deadline.min(deadline)) => {
+          if Instant::now() >= deadline {
+            return Err(RelayTimeout::Lifetime);
+          }
+          // Traffic may have happened meanwhile, wait for the updated idle deadline.
+          if self.last() + idle <= Instant::now() {
+            return Err(RelayTimeout::Idle(idle));
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Stream wrapper touching the activity on every successful read or write.
+pub struct ActivityStream<S> {
+  inner: S,
+  activity: Activity,
+}
+
+impl<S> ActivityStream<S> {
+  pub fn new(inner: S, activity: Activity) -> Self {
+    Self { inner, activity }
+  }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityStream<S> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    if let Poll::Ready(Ok(())) = result {
+      self.activity.touch();
+    }
+    result
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<S> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+    if let Poll::Ready(Ok(_)) = result {
+      self.activity.touch();
+    }
+    result
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
@@ -0,0 +1,51 @@
+use std::{
+  io,
+  pin::Pin,
+  sync::Arc,
+  task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::cache::usage::BandwidthCounter;
+
+/// Outbound stream wrapper accounting every byte read from (in) and written to (out) the target.
+pub struct MeteredStream<S> {
+  inner: S,
+  counter: Arc<BandwidthCounter>,
+}
+
+impl<S> MeteredStream<S> {
+  pub fn new(inner: S, counter: Arc<BandwidthCounter>) -> Self {
+    Self { inner, counter }
+  }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    if let Poll::Ready(Ok(())) = result {
+      self.counter.add_in((buf.filled().len() - filled) as u64);
+    }
+    result
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+    if let Poll::Ready(Ok(written)) = result {
+      self.counter.add_out(written as u64);
+    }
+    result
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
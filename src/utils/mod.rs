@@ -1,4 +1,11 @@
 pub mod auth;
 pub mod config;
 pub mod constants;
+pub mod digest;
+pub mod header_rule;
+pub mod idle;
+pub mod metered;
+pub mod password;
+pub mod rewind;
 pub mod socket;
+pub mod tls;
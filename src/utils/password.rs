@@ -0,0 +1,59 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+const ARGON2_PREFIX: &str = "$argon2";
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+// Format: $sha256$<salt>$<hex(sha256(salt + password))>
+const SHA256_PREFIX: &str = "$sha256$";
+
+/// Returns true if the stored password is a hash, which is expensive to verify depending on the scheme.
+pub fn is_hashed(stored: &str) -> bool {
+  stored.starts_with(ARGON2_PREFIX) || stored.starts_with(SHA256_PREFIX) || BCRYPT_PREFIXES.iter().any(|p| stored.starts_with(p))
+}
+
+/// Verifies the provided password against the stored one, either a hash detected by its prefix or plaintext.
+pub fn verify_password(stored: &str, provided: &str) -> bool {
+  if stored.starts_with(ARGON2_PREFIX) {
+    return match PasswordHash::new(stored) {
+      Ok(hash) => Argon2::default().verify_password(provided.as_bytes(), &hash).is_ok(),
+      Err(e) => {
+        warn!("invalid argon2 password hash. Err = {}", e);
+        false
+      }
+    };
+  }
+
+  if BCRYPT_PREFIXES.iter().any(|p| stored.starts_with(p)) {
+    return match bcrypt::verify(provided, stored) {
+      Ok(valid) => valid,
+      Err(e) => {
+        warn!("invalid bcrypt password hash. Err = {}", e);
+        false
+      }
+    };
+  }
+
+  if let Some(salted) = stored.strip_prefix(SHA256_PREFIX) {
+    let (salt, digest) = match salted.split_once('$') {
+      Some(parts) => parts,
+      None => {
+        warn!("invalid sha256 password hash, expected $sha256$<salt>$<hex>");
+        return false;
+      }
+    };
+    let expected = match hex::decode(digest) {
+      Ok(expected) => expected,
+      Err(e) => {
+        warn!("invalid sha256 password hash digest. Err = {}", e);
+        return false;
+      }
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(provided.as_bytes());
+    return hasher.finalize().as_slice().ct_eq(&expected).into();
+  }
+
+  stored.as_bytes().ct_eq(provided.as_bytes()).into()
+}
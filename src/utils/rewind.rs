@@ -0,0 +1,46 @@
+use std::{
+  io,
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream replaying the bytes already read to detect the protocol before reading further from the inner stream.
+pub struct Rewind<S> {
+  prefix: Bytes,
+  inner: S,
+}
+
+impl<S> Rewind<S> {
+  pub fn new(prefix: Bytes, inner: S) -> Self {
+    Self { prefix, inner }
+  }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    if !self.prefix.is_empty() {
+      let len = self.prefix.len().min(buf.remaining());
+      buf.put_slice(&self.prefix[..len]);
+      self.prefix.advance(len);
+      return Poll::Ready(Ok(()));
+    }
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
@@ -0,0 +1,46 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use thiserror::Error;
+use tokio_rustls::{
+  rustls::{self, crypto::ring, ServerConfig},
+  TlsAcceptor,
+};
+
+#[derive(Error, Debug)]
+pub enum TlsConfigError {
+  #[error("failed to read {0}. Err = {1}")]
+  Read(String, std::io::Error),
+  #[error("no certificate found in {0}")]
+  MissingCertificate(String),
+  #[error("no private key found in {0}")]
+  MissingKey(String),
+  #[error("invalid certificate or private key. Err = {0}")]
+  Rustls(#[from] rustls::Error),
+}
+
+/// Builds the acceptor of the HTTPS proxy listeners from PEM files, the certificate file may hold the whole chain.
+/// HTTP/2 is only offered with `http2`, clients negotiating it send every request over it.
+pub fn load_acceptor(cert_path: &str, key_path: &str, http2: bool) -> Result<TlsAcceptor, TlsConfigError> {
+  let mut cert_reader = BufReader::new(File::open(cert_path).map_err(|e| TlsConfigError::Read(cert_path.to_string(), e))?);
+  let certs = rustls_pemfile::certs(&mut cert_reader)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| TlsConfigError::Read(cert_path.to_string(), e))?;
+  if certs.is_empty() {
+    return Err(TlsConfigError::MissingCertificate(cert_path.to_string()));
+  }
+
+  let mut key_reader = BufReader::new(File::open(key_path).map_err(|e| TlsConfigError::Read(key_path.to_string(), e))?);
+  let key = rustls_pemfile::private_key(&mut key_reader)
+    .map_err(|e| TlsConfigError::Read(key_path.to_string(), e))?
+    .ok_or_else(|| TlsConfigError::MissingKey(key_path.to_string()))?;
+
+  let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+  config.alpn_protocols = match http2 {
+    true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    false => vec![b"http/1.1".to_vec()],
+  };
+  Ok(TlsAcceptor::from(Arc::new(config)))
+}